@@ -0,0 +1,196 @@
+//! Compares the codec in `shannon_codec`, which reuses its buffers and shares
+//! the decoded payloads, with the previous implementation allocating a new
+//! buffer for each frame and copying the payload parts out of it.
+
+use std::io::{self, Cursor, Read, Write};
+
+use byteorder::{ReadBytesExt, BE};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use psst_core::{
+    connection::shannon_codec::{ShannonDecoder, ShannonEncoder},
+    util::read_bytes,
+};
+use shannon::Shannon;
+
+const KEY: &[u8] = b"benchmark key";
+const CMD: u8 = 0xb2;
+const STREAM_SIZE: usize = 1024 * 1024;
+const PAYLOAD_SIZES: &[usize] = &[128, 16 * 1024];
+// Payloads are split into this many length-prefixed parts, like Mercury
+// messages.
+const PARTS: usize = 4;
+
+/// Encoder allocating the frame for every message.
+struct AllocatingEncoder<T> {
+    inner: T,
+    nonce: u32,
+    cipher: Shannon,
+}
+
+impl<T: Write> AllocatingEncoder<T> {
+    fn new(inner: T, key: &[u8]) -> Self {
+        Self {
+            inner,
+            nonce: 0,
+            cipher: Shannon::new(key),
+        }
+    }
+
+    fn encode(&mut self, cmd: u8, payload: Vec<u8>) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(3 + payload.len() + 4);
+        buf.push(cmd);
+        buf.extend((payload.len() as u16).to_be_bytes());
+        buf.extend(payload);
+
+        self.cipher.nonce_u32(self.nonce);
+        self.nonce += 1;
+        self.cipher.encrypt(&mut buf);
+
+        let mut mac = [0_u8; 4];
+        self.cipher.finish(&mut mac);
+        buf.extend(mac);
+        self.inner.write_all(&buf)
+    }
+}
+
+/// Decoder allocating the payload of every frame.
+struct AllocatingDecoder<T> {
+    inner: T,
+    nonce: u32,
+    cipher: Shannon,
+}
+
+impl<T: Read> AllocatingDecoder<T> {
+    fn new(inner: T, key: &[u8]) -> Self {
+        Self {
+            inner,
+            nonce: 0,
+            cipher: Shannon::new(key),
+        }
+    }
+
+    fn decode(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut header = [0_u8; 3];
+        self.inner.read_exact(&mut header)?;
+        self.cipher.nonce_u32(self.nonce);
+        self.nonce += 1;
+        self.cipher.decrypt(&mut header);
+        let size = u16::from_be_bytes([header[1], header[2]]) as usize;
+
+        let mut payload = vec![0_u8; size];
+        self.inner.read_exact(&mut payload)?;
+        self.cipher.decrypt(&mut payload);
+
+        let mut mac = [0_u8; 4];
+        self.inner.read_exact(&mut mac)?;
+        self.cipher.check_mac(&mac)?;
+
+        Ok((header[0], payload))
+    }
+}
+
+/// Payload of `size` bytes made of `PARTS` length-prefixed parts.
+fn payload(size: usize) -> Vec<u8> {
+    let part_len = size / PARTS - 2;
+    let mut payload = Vec::with_capacity(size);
+    for _ in 0..PARTS {
+        payload.extend((part_len as u16).to_be_bytes());
+        payload.resize(payload.len() + part_len, 0xaa);
+    }
+    payload
+}
+
+fn copy_parts(payload: Vec<u8>) -> io::Result<Vec<Vec<u8>>> {
+    let mut cursor = Cursor::new(payload);
+    (0..PARTS)
+        .map(|_| {
+            let mut part = vec![0_u8; cursor.read_u16::<BE>()?.into()];
+            cursor.read_exact(&mut part)?;
+            Ok(part)
+        })
+        .collect()
+}
+
+fn share_parts(payload: Bytes) -> io::Result<Vec<Bytes>> {
+    let mut cursor = Cursor::new(payload);
+    (0..PARTS)
+        .map(|_| {
+            let len = cursor.read_u16::<BE>()?;
+            read_bytes(&mut cursor, len.into())
+        })
+        .collect()
+}
+
+fn encoded_stream(payload: &[u8], frames: usize) -> Vec<u8> {
+    let mut encoder = ShannonEncoder::new(Vec::new(), KEY);
+    for _ in 0..frames {
+        encoder.encode_slice(CMD, payload).unwrap();
+    }
+    std::mem::take(encoder.as_inner_mut())
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for &size in PAYLOAD_SIZES {
+        let payload = payload(size);
+        let frames = STREAM_SIZE / size;
+        let mut stream = Vec::with_capacity(frames * (size + 7));
+        group.throughput(Throughput::Bytes(STREAM_SIZE as u64));
+        group.bench_function(format!("allocating/{}", size), |b| {
+            b.iter(|| {
+                stream.clear();
+                let mut encoder = AllocatingEncoder::new(&mut stream, KEY);
+                for _ in 0..frames {
+                    encoder.encode(CMD, payload.clone()).unwrap();
+                }
+            })
+        });
+        group.bench_function(format!("reusing/{}", size), |b| {
+            b.iter(|| {
+                stream.clear();
+                let mut encoder = ShannonEncoder::new(&mut stream, KEY);
+                for _ in 0..frames {
+                    encoder.encode_slice(CMD, &payload).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for &size in PAYLOAD_SIZES {
+        let frames = STREAM_SIZE / size;
+        let stream = encoded_stream(&payload(size), frames);
+        group.throughput(Throughput::Bytes(STREAM_SIZE as u64));
+        group.bench_function(format!("allocating/{}", size), |b| {
+            b.iter_batched_ref(
+                || AllocatingDecoder::new(Cursor::new(&stream), KEY),
+                |decoder| {
+                    for _ in 0..frames {
+                        let (_, payload) = decoder.decode().unwrap();
+                        copy_parts(payload).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(format!("pooled/{}", size), |b| {
+            b.iter_batched_ref(
+                || ShannonDecoder::new(Cursor::new(&stream), KEY),
+                |decoder| {
+                    for _ in 0..frames {
+                        share_parts(decoder.decode().unwrap().payload).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
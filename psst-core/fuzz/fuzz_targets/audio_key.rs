@@ -0,0 +1,32 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use psst_core::{
+    connection::shannon_codec::ShannonMsg,
+    item_id::{FileId, ItemId, ItemIdType},
+    session::{audio_key::AudioKeyDispatcher, response::response_channel, DEFAULT_REQUEST_TIMEOUT},
+};
+
+fuzz_target!(|frames: Vec<(bool, Vec<u8>)>| {
+    let mut dispatcher = AudioKeyDispatcher::new();
+    // Keep a few requests pending, so the frames have something to match.
+    let pending: Vec<_> = (0..4)
+        .map(|id| {
+            let (callback, pending) = response_channel(DEFAULT_REQUEST_TIMEOUT);
+            dispatcher.enqueue_request(
+                ItemId::new(id, ItemIdType::Track),
+                FileId([0; 20]),
+                callback,
+            );
+            pending
+        })
+        .collect();
+    for (is_error, payload) in frames {
+        if is_error {
+            dispatcher.handle_aes_key_error(ShannonMsg::new(ShannonMsg::AES_KEY_ERROR, payload));
+        } else {
+            dispatcher.handle_aes_key(ShannonMsg::new(ShannonMsg::AES_KEY, payload));
+        }
+    }
+    drop(pending);
+});
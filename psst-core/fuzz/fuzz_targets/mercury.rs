@@ -0,0 +1,31 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use psst_core::{
+    connection::shannon_codec::ShannonMsg,
+    session::{
+        mercury::{MercuryDispatcher, MercuryRequest},
+        response::response_channel,
+        DEFAULT_REQUEST_TIMEOUT,
+    },
+};
+
+fuzz_target!(|frames: Vec<(bool, Vec<u8>)>| {
+    let mut dispatcher = MercuryDispatcher::new();
+    // Keep a few requests pending, so the frames have something to match.
+    let pending: Vec<_> = (0..4)
+        .map(|_| {
+            let (callback, pending) = response_channel(DEFAULT_REQUEST_TIMEOUT);
+            dispatcher.enqueue_request(MercuryRequest::get("hm://fuzz".into()), callback);
+            pending
+        })
+        .collect();
+    for (is_pub, payload) in frames {
+        if is_pub {
+            dispatcher.handle_mercury_pub(ShannonMsg::new(ShannonMsg::MERCURY_PUB, payload));
+        } else {
+            dispatcher.handle_mercury_req(ShannonMsg::new(ShannonMsg::MERCURY_REQ, payload));
+        }
+    }
+    drop(pending);
+});
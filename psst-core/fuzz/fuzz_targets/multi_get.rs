@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use psst_core::session::mercury::MercuryResponse;
+
+fuzz_target!(|payload: Vec<Vec<u8>>| {
+    let response = MercuryResponse {
+        uri: "hm://fuzz".into(),
+        status_code: 200,
+        content_type: None,
+        user_fields: Vec::new(),
+        payload: payload.into_iter().map(Into::into).collect(),
+    };
+    let _ = response.into_multi_get_results();
+});
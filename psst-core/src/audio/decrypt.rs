@@ -1,4 +1,7 @@
-use std::{convert::TryInto, io};
+use std::{
+    convert::TryInto,
+    io::{self, Read, Seek, SeekFrom},
+};
 
 use aes::{
     cipher::{generic_array::GenericArray, KeyIvInit, StreamCipher, StreamCipherSeek},
@@ -10,6 +13,11 @@ const AUDIO_AESIV: [u8; 16] = [
     0x72, 0xe0, 0x67, 0xfb, 0xdd, 0xcb, 0xcf, 0x77, 0xeb, 0xe8, 0xbc, 0x64, 0x3f, 0x63, 0x0d, 0x93,
 ];
 
+// Spotify prepends its own header to the Ogg files, the Ogg stream starts at
+// this offset.
+pub const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+pub const OGG_MAGIC: &[u8] = b"OggS";
+
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
 pub struct AudioKey(pub [u8; 16]);
 
@@ -32,6 +40,10 @@ impl<T: io::Read> AudioDecrypt<T> {
         );
         AudioDecrypt { cipher, reader }
     }
+
+    pub fn into_inner(self) -> T {
+        self.reader
+    }
 }
 
 impl<T: io::Read> io::Read for AudioDecrypt<T> {
@@ -44,6 +56,21 @@ impl<T: io::Read> io::Read for AudioDecrypt<T> {
     }
 }
 
+impl<T: Read + Seek> AudioDecrypt<T> {
+    /// Check that the reader is decrypted into an Ogg stream, and rewind it.
+    pub fn starts_with_ogg(&mut self) -> io::Result<bool> {
+        let mut magic = [0; OGG_MAGIC.len()];
+        self.seek(SeekFrom::Start(SPOTIFY_OGG_HEADER_END))?;
+        let result = self.read_exact(&mut magic);
+        self.seek(SeekFrom::Start(0))?;
+        match result {
+            Ok(()) => Ok(magic == OGG_MAGIC),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
 impl<T: io::Read + io::Seek> io::Seek for AudioDecrypt<T> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
         let newpos = self.reader.seek(pos)?;
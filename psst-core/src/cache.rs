@@ -1,4 +1,17 @@
-use std::{fs, io, path::Path};
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+
+use crate::{
+    audio::decrypt::{AudioDecrypt, AudioKey},
+    error::Error,
+    item_id::{FileId, ItemId},
+};
 
 pub fn mkdir_if_not_exists(path: &Path) -> io::Result<()> {
     fs::create_dir(path).or_else(|err| {
@@ -9,3 +22,175 @@ pub fn mkdir_if_not_exists(path: &Path) -> io::Result<()> {
         }
     })
 }
+
+/// On-disk store of audio keys, so tracks played before do not need a key
+/// round-trip to the access point.
+pub struct AudioKeyCache {
+    base: PathBuf,
+}
+
+impl AudioKeyCache {
+    /// Keys are stored in the `key` directory under `base`, only accessible
+    /// by the current user.  Missing parent directories are created.
+    pub fn new(base: &Path) -> io::Result<Self> {
+        fs::create_dir_all(base)?;
+        let base = base.join("key");
+        let mut builder = DirBuilder::new();
+        #[cfg(target_family = "unix")]
+        builder.mode(0o700);
+        match builder.create(&base) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => {}
+        }
+        // The directory might have been created with the default mode before.
+        #[cfg(target_family = "unix")]
+        fs::set_permissions(&base, fs::Permissions::from_mode(0o700))?;
+        Ok(Self { base })
+    }
+
+    pub fn get(&self, track: ItemId, file: FileId) -> Option<AudioKey> {
+        let data = fs::read(self.key_path(track, file)).ok()?;
+        AudioKey::from_raw(&data)
+    }
+
+    pub fn save(&self, track: ItemId, file: FileId, key: AudioKey) -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(target_family = "unix")]
+        options.mode(0o600);
+
+        let mut file = options.open(self.key_path(track, file))?;
+        file.write_all(&key.0)
+    }
+
+    /// Forget a key, i.e. after it failed to decrypt the audio file.
+    pub fn remove(&self, track: ItemId, file: FileId) -> io::Result<()> {
+        match fs::remove_file(self.key_path(track, file)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the cached key, or get it from `request` and cache it.
+    pub fn get_or_request(
+        &self,
+        track: ItemId,
+        file: FileId,
+        request: impl FnOnce() -> Result<AudioKey, Error>,
+    ) -> Result<AudioKey, Error> {
+        if let Some(key) = self.get(track, file) {
+            return Ok(key);
+        }
+        let key = request()?;
+        if let Err(err) = self.save(track, file, key) {
+            log::warn!("failed to save audio key to cache: {:?}", err);
+        }
+        Ok(key)
+    }
+
+    /// Decrypt the Ogg file `encrypted` with the key from `get_or_request`.  If
+    /// the key does not decrypt it, e.g. because a damaged key was cached, it
+    /// is removed and requested once more.
+    pub fn decrypt<R: Read + Seek>(
+        &self,
+        track: ItemId,
+        file: FileId,
+        mut encrypted: R,
+        mut request: impl FnMut() -> Result<AudioKey, Error>,
+    ) -> Result<AudioDecrypt<R>, Error> {
+        for _ in 0..2 {
+            let key = self.get_or_request(track, file, &mut request)?;
+            let mut decrypted = AudioDecrypt::new(key, encrypted);
+            if decrypted.starts_with_ogg()? {
+                return Ok(decrypted);
+            }
+            log::warn!("audio key does not decrypt file {}", file.to_base16());
+            if let Err(err) = self.remove(track, file) {
+                log::warn!("failed to remove audio key from cache: {:?}", err);
+            }
+            encrypted = decrypted.into_inner();
+        }
+        Err(Error::AudioDecodingError(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "audio key does not decrypt the file",
+        ))))
+    }
+
+    fn key_path(&self, track: ItemId, file: FileId) -> PathBuf {
+        self.base
+            .join(format!("{}{}", track.to_base16(), file.to_base16()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        process,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::{
+        audio::decrypt::{OGG_MAGIC, SPOTIFY_OGG_HEADER_END},
+        item_id::ItemIdType,
+    };
+
+    /// Fresh directory for a single test, fails instead of reusing one left
+    /// behind by another run.
+    fn unique_temp_dir() -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("psst-cache-test-{}-{}", process::id(), nanos));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    /// Ogg file encrypted with `key`.
+    fn encrypted_file(key: AudioKey) -> Vec<u8> {
+        let mut plain = vec![0; SPOTIFY_OGG_HEADER_END as usize];
+        plain.extend(OGG_MAGIC);
+        plain.resize(1024, 0);
+        let mut file = vec![0; plain.len()];
+        AudioDecrypt::new(key, &plain[..])
+            .read_exact(&mut file)
+            .unwrap();
+        file
+    }
+
+    #[test]
+    fn requests_audio_key_again_if_the_cached_one_does_not_decrypt() {
+        let dir = unique_temp_dir();
+        let cache = AudioKeyCache::new(&dir.join("missing").join("parent")).unwrap();
+        #[cfg(target_family = "unix")]
+        {
+            let key_dir = dir.join("missing").join("parent").join("key");
+            let mode = fs::metadata(key_dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        let track = ItemId::new(1, ItemIdType::Track);
+        let file = FileId([1; 20]);
+        let key = AudioKey([7; 16]);
+        cache.save(track, file, AudioKey([8; 16])).unwrap();
+
+        let mut key_requests = 0;
+        let mut request = || {
+            key_requests += 1;
+            Ok(key)
+        };
+        let encrypted = Cursor::new(encrypted_file(key));
+        let mut decrypted = cache.decrypt(track, file, encrypted, &mut request).unwrap();
+        let mut header = [0; SPOTIFY_OGG_HEADER_END as usize + 4];
+        decrypted.read_exact(&mut header).unwrap();
+        assert!(header.ends_with(OGG_MAGIC));
+        assert_eq!(cache.get(track, file), Some(key));
+
+        // The correct key is cached now.
+        let encrypted = decrypted.into_inner();
+        cache.decrypt(track, file, encrypted, &mut request).unwrap();
+        assert_eq!(key_requests, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::{io, time::Instant};
+
+use psst_protocol::authentication::APWelcome;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    task,
+    time::timeout,
+};
+
+use crate::{error::Error, util::NET_CONNECT_TIMEOUT};
+
+use super::{
+    client_response_encrypted, handshake_packet_size, parse_auth_response,
+    proxy::connect_through_proxy,
+    shannon_codec::{AsyncShannonDecoder, AsyncShannonEncoder},
+    Credentials, Handshake,
+};
+
+/// Like `Transport`, but over a `tokio` TCP stream.  Dropping both the encoder
+/// and the decoder closes the connection.
+pub struct AsyncTransport {
+    pub encoder: AsyncShannonEncoder<OwnedWriteHalf>,
+    pub decoder: AsyncShannonDecoder<OwnedReadHalf>,
+}
+
+impl AsyncTransport {
+    pub async fn connect(ap: &str, proxy_url: Option<&str>) -> Result<Self, Error> {
+        log::trace!("connecting to: {:?} with proxy: {:?}", ap, proxy_url);
+        let deadline = Instant::now() + NET_CONNECT_TIMEOUT;
+        let stream = if let Some(url) = proxy_url {
+            // The proxy handshake is blocking, run it off the async workers and
+            // take over the established stream.
+            let ap = ap.to_string();
+            let url = url.to_string();
+            let stream = task::spawn_blocking(move || connect_through_proxy(&ap, &url, deadline))
+                .await
+                .map_err(io::Error::other)??;
+            stream.set_nonblocking(true)?;
+            TcpStream::from_std(stream)?
+        } else {
+            timeout(NET_CONNECT_TIMEOUT, TcpStream::connect(ap))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??
+        };
+        log::trace!("connected");
+        Self::exchange_keys(stream, deadline).await
+    }
+
+    /// Like `Transport::exchange_keys`.
+    pub async fn exchange_keys(mut stream: TcpStream, deadline: Instant) -> Result<Self, Error> {
+        log::trace!("sending client hello");
+        let handshake = Handshake::start();
+        stream.write_all(&handshake.hello_packet).await?;
+
+        log::trace!("waiting for AP response");
+        let apresp_packet = read_packet(&mut stream).await?;
+
+        log::trace!("sending client response");
+        // Solving the proof-of-work challenge takes a while, keep it off the
+        // async workers.
+        let keys = task::spawn_blocking(move || handshake.finish(&apresp_packet, deadline))
+            .await
+            .map_err(io::Error::other)??;
+        stream.write_all(&keys.response_packet).await?;
+
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            encoder: AsyncShannonEncoder::new(writer, &keys.send_key),
+            decoder: AsyncShannonDecoder::new(reader, &keys.recv_key),
+        })
+    }
+
+    /// Log in with `credentials`, see `Transport::authenticate`.
+    pub async fn authenticate(&mut self, credentials: Credentials) -> Result<APWelcome, Error> {
+        let request = client_response_encrypted(credentials);
+        self.encoder.encode(request).await?;
+        let response = self.decoder.decode().await?;
+        parse_auth_response(response)
+    }
+}
+
+async fn read_packet(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let size = stream.read_u32().await?;
+    let mut buf = vec![0_u8; handshake_packet_size(size)?];
+    let (size_buf, data_buf) = buf.split_at_mut(4);
+    size_buf.copy_from_slice(&size.to_be_bytes());
+    stream.read_exact(data_buf).await?;
+    Ok(buf)
+}
@@ -0,0 +1,160 @@
+use std::time::Instant;
+
+use sha1::{Digest, Sha1};
+
+// Highest difficulty we are willing to solve.  Every bit doubles the expected
+// number of hashes.
+const MAX_TARGET: u32 = 32;
+
+// Longest suffix we accept.  Real challenges ask for 16 bytes.
+const MAX_LENGTH: usize = 64;
+
+// Number of hashes between the checks of the deadline.
+const DEADLINE_CHECK_INTERVAL: u64 = 4096;
+
+/// Find a suffix of `length` bytes, such that the SHA-1 digest of `prefix`
+/// followed by the suffix starts with at least `target` zero bits.  The suffix
+/// is a big-endian counter, so the first (lowest) solution is always returned.
+/// Returns `None` if the challenge is too hard, has no solution, or is not
+/// solved before `deadline`.
+pub fn solve(prefix: &[u8], length: usize, target: u32, deadline: Instant) -> Option<Vec<u8>> {
+    if length == 0 || length > MAX_LENGTH || target > MAX_TARGET {
+        return None;
+    }
+    let counter_len = length.min(8);
+    let counter_max = if counter_len == 8 {
+        u64::MAX
+    } else {
+        (1 << (8 * counter_len)) - 1
+    };
+    let mut suffix = vec![0_u8; length];
+    for counter in 0..=counter_max {
+        if counter % DEADLINE_CHECK_INTERVAL == 0 && Instant::now() >= deadline {
+            return None;
+        }
+        suffix[length - counter_len..].copy_from_slice(&counter.to_be_bytes()[8 - counter_len..]);
+        if is_solution(prefix, &suffix, target) {
+            return Some(suffix);
+        }
+    }
+    None
+}
+
+/// Returns true if `suffix` solves the challenge given by `prefix` and `target`.
+pub fn is_solution(prefix: &[u8], suffix: &[u8], target: u32) -> bool {
+    let digest = Sha1::new()
+        .chain_update(prefix)
+        .chain_update(suffix)
+        .finalize();
+    leading_zero_bits(&digest) >= target
+}
+
+fn leading_zero_bits(data: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in data {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn no_deadline() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn solves_known_challenges() {
+        // Lowest solutions, cross-checked with a search over Python's
+        // `hashlib.sha1`.
+        let vectors = [
+            (
+                "0011223344556677",
+                16,
+                8,
+                "000000000000000000000000000000e6",
+            ),
+            ("70737374", 16, 16, "000000000000000000000000000013ac"),
+            (
+                "000102030405060708090a0b0c0d0e0f",
+                8,
+                12,
+                "000000000000070b",
+            ),
+            ("", 16, 0, "00000000000000000000000000000000"),
+        ];
+        for (prefix, length, target, suffix) in vectors {
+            assert_eq!(
+                solve(&hex(prefix), length, target, no_deadline()),
+                Some(hex(suffix))
+            );
+        }
+    }
+
+    #[test]
+    fn verifies_solutions() {
+        let prefix = hex("70737374");
+        assert!(is_solution(
+            &prefix,
+            &hex("000000000000000000000000000013ac"),
+            16
+        ));
+        assert!(!is_solution(
+            &prefix,
+            &hex("000000000000000000000000000013ab"),
+            16
+        ));
+    }
+
+    #[test]
+    fn verifies_reference_stamp() {
+        // Example hashcash v1 stamp worth 20 bits, as published in the
+        // Wikipedia article on Hashcash.  Its SHA-1 is 00000b7c65ac...
+        let prefix = b"1:20:1303030600:adam@cypherspace.org::McMybZIhxKXu57jd:";
+        assert!(is_solution(prefix, b"ckvi", 20));
+        assert!(!is_solution(prefix, b"ckvi", 21));
+        assert!(!is_solution(prefix, b"ckvj", 20));
+    }
+
+    #[test]
+    fn rejects_unreasonable_challenges() {
+        assert_eq!(solve(b"prefix", 16, MAX_TARGET + 1, no_deadline()), None);
+        assert_eq!(solve(b"prefix", 0, 8, no_deadline()), None);
+        assert_eq!(solve(b"prefix", MAX_LENGTH + 1, 8, no_deadline()), None);
+    }
+
+    #[test]
+    fn gives_up_at_the_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(solve(b"prefix", 16, MAX_TARGET, deadline), None);
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn gives_up_when_the_suffix_space_is_exhausted() {
+        // A single byte has 256 candidates, far from enough for 32 zero bits.
+        assert_eq!(solve(b"prefix", 1, 32, no_deadline()), None);
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x80]), 16);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f, 0xff]), 12);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+    }
+}
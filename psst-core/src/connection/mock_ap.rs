@@ -0,0 +1,719 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Condvar, Mutex};
+use psst_protocol::{
+    authentication::{APWelcome, AuthenticationType, ClientResponseEncrypted},
+    keyexchange::{
+        APChallenge, APLoginFailed, APResponseMessage, ClientHello, ClientResponsePlaintext,
+        ErrorCode, LoginCryptoChallengeUnion, LoginCryptoDiffieHellmanChallenge, PoWChallengeUnion,
+        PoWHashCashChallenge,
+    },
+    mercury::Header,
+};
+
+use crate::{
+    audio::decrypt::AudioKey,
+    error::Error,
+    item_id::FileId,
+    session::{mercury::Msg, SessionConfig, DEFAULT_REQUEST_TIMEOUT},
+    util::{deserialize_protobuf, serialize_protobuf},
+};
+
+use super::{
+    compute_keys,
+    diffie_hellman::DHLocalKeys,
+    hashcash, make_packet, read_packet,
+    shannon_codec::{ShannonDecoder, ShannonEncoder, ShannonMsg},
+    Credentials,
+};
+
+// Size of the Mercury message header with an 8-byte sequence number, and of
+// the length prefix of every part.
+const MERCURY_HEADER_SIZE: usize = 2 + 8 + 1 + 2;
+const MERCURY_PART_LEN_SIZE: usize = 2;
+
+// Smallest Mercury frame still fitting a byte of a part, and the largest
+// payload of a Shannon frame.
+const MIN_MERCURY_FRAME_SIZE: usize = MERCURY_HEADER_SIZE + 2 * MERCURY_PART_LEN_SIZE + 1;
+const MAX_MERCURY_FRAME_SIZE: usize = u16::MAX as usize;
+
+// Error code of the audio key errors we send.
+const AUDIO_KEY_ERROR_CODE: u16 = 1;
+
+/// Local access point speaking enough of the AP protocol to test the
+/// connection and session code without network: the key exchange, Shannon
+/// framing, login, Mercury, audio keys and pings.  Replies are scripted through
+/// the `set_*` and `push_*` methods, and can be changed while clients are
+/// connected.  Listens on a random local port until dropped.
+pub struct MockAp {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    accepting_thread: Option<JoinHandle<()>>,
+}
+
+/// Reply to a login request.
+#[derive(Debug)]
+pub enum LoginReply {
+    Welcome(APWelcome),
+    Failed(ErrorCode),
+    /// Close the connection without replying.
+    Disconnect,
+    /// Keep the connection open without replying.
+    Silent,
+    /// Reply with an arbitrary message, e.g. to test protocol errors.
+    Message(ShannonMsg),
+}
+
+/// Reply to a Mercury request.
+#[derive(Clone, Debug)]
+pub enum MercuryReply {
+    Response {
+        status_code: i32,
+        payload: Vec<Vec<u8>>,
+    },
+    /// Never reply, so the request times out.
+    Silent,
+}
+
+impl MercuryReply {
+    pub fn ok(payload: Vec<Vec<u8>>) -> Self {
+        Self::Response {
+            status_code: 200,
+            payload,
+        }
+    }
+
+    pub fn status(status_code: i32) -> Self {
+        Self::Response {
+            status_code,
+            payload: Vec::new(),
+        }
+    }
+}
+
+/// Reply to an audio key request.
+#[derive(Clone, Debug)]
+pub enum AudioKeyReply {
+    Key(AudioKey),
+    Error,
+    /// Never reply, so the request times out.
+    Silent,
+}
+
+/// Request received by the `MockAp`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockRequest {
+    Login {
+        username: Option<String>,
+        auth_type: AuthenticationType,
+        auth_data: Vec<u8>,
+    },
+    Mercury {
+        method: String,
+        uri: String,
+        payload: Vec<Vec<u8>>,
+    },
+    AudioKey {
+        file: FileId,
+    },
+    Pong,
+    Other {
+        cmd: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// A `MockRequest` together with the index of the connection it arrived on,
+/// counting the accepted connections from zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Received {
+    pub connection: usize,
+    pub request: MockRequest,
+}
+
+struct Shared {
+    script: Mutex<Script>,
+    log: Mutex<Log>,
+    changed: Condvar,
+    connections: Mutex<Vec<Arc<Connection>>>,
+    publish_sequence: AtomicU64,
+    stopped: AtomicBool,
+}
+
+struct Script {
+    logins: VecDeque<LoginReply>,
+    mercury: HashMap<String, MercuryReply>,
+    audio_keys: HashMap<FileId, AudioKeyReply>,
+    country_code: Option<String>,
+    hash_cash: Option<(i32, i32)>,
+    mercury_frame_size: usize,
+}
+
+#[derive(Default)]
+struct Log {
+    connections: usize,
+    requests: Vec<Received>,
+}
+
+struct Connection {
+    index: usize,
+    stream: TcpStream,
+    // Set once the client is logged in.
+    encoder: Mutex<Option<ShannonEncoder<TcpStream>>>,
+}
+
+impl MockAp {
+    /// Start listening on a random port of the loopback interface.
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            script: Mutex::new(Script {
+                logins: VecDeque::new(),
+                mercury: HashMap::new(),
+                audio_keys: HashMap::new(),
+                country_code: None,
+                hash_cash: None,
+                mercury_frame_size: MAX_MERCURY_FRAME_SIZE,
+            }),
+            log: Mutex::default(),
+            changed: Condvar::new(),
+            connections: Mutex::default(),
+            publish_sequence: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+        });
+        let accepting_thread = {
+            let shared = shared.clone();
+            thread::spawn(move || accept_connections(listener, shared))
+        };
+        Ok(Self {
+            addr,
+            shared,
+            accepting_thread: Some(accepting_thread),
+        })
+    }
+
+    /// Address to connect to, i.e. to pin in `SessionConfig`.
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Session config connecting to this AP only.
+    pub fn session_config(&self, login_creds: Credentials) -> SessionConfig {
+        SessionConfig {
+            login_creds,
+            proxy_url: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            pinned_ap: Some(self.addr()),
+            last_ap_path: None,
+            audio_key_cache_dir: None,
+        }
+    }
+
+    /// Reply to the next login with `reply`.  Replies are used in the order
+    /// they were pushed, logins without a pushed reply are accepted.
+    pub fn push_login_reply(&self, reply: LoginReply) {
+        self.shared.script.lock().logins.push_back(reply);
+    }
+
+    /// Reply to all requests for `uri` with `reply`.  Unless set, GET and other
+    /// requests fail with 404, SUB and UNSUB requests succeed.
+    pub fn set_mercury_reply(&self, uri: impl Into<String>, reply: MercuryReply) {
+        self.shared.script.lock().mercury.insert(uri.into(), reply);
+    }
+
+    /// Reply to all key requests for `file` with `reply`.  Unless set, the
+    /// requests fail.
+    pub fn set_audio_key_reply(&self, file: FileId, reply: AudioKeyReply) {
+        self.shared.script.lock().audio_keys.insert(file, reply);
+    }
+
+    /// Send `country_code` after every successful login.
+    pub fn set_country_code(&self, country_code: impl Into<String>) {
+        self.shared.script.lock().country_code = Some(country_code.into());
+    }
+
+    /// Ask the clients to solve a hashcash challenge of `length` bytes with
+    /// `target` zero bits during the key exchange.
+    pub fn set_hash_cash(&self, length: i32, target: i32) {
+        self.shared.script.lock().hash_cash = Some((length, target));
+    }
+
+    /// Split Mercury replies and events into frames of at most `size` bytes,
+    /// to exercise joining of the parts split across frames.
+    pub fn set_mercury_frame_size(&self, size: usize) {
+        self.shared.script.lock().mercury_frame_size =
+            size.clamp(MIN_MERCURY_FRAME_SIZE, MAX_MERCURY_FRAME_SIZE);
+    }
+
+    /// Ping all logged-in clients.
+    pub fn send_ping(&self) {
+        self.send(ShannonMsg::PING, &[0, 0, 0, 0]);
+    }
+
+    /// Publish a Mercury event to all logged-in clients.
+    pub fn publish(&self, uri: impl Into<String>, payload: Vec<Vec<u8>>) {
+        let header = Header {
+            uri: Some(uri.into()),
+            ..Header::default()
+        };
+        let header = serialize_protobuf(&header).expect("Failed to serialize");
+        let parts: Vec<&[u8]> = std::iter::once(header.as_slice())
+            .chain(payload.iter().map(Vec::as_slice))
+            .collect();
+        let seq = self.shared.publish_sequence.fetch_add(1, Ordering::SeqCst);
+        let frame_size = self.shared.script.lock().mercury_frame_size;
+        for frame in mercury_frames(seq, &parts, frame_size) {
+            self.send(ShannonMsg::MERCURY_PUB, &frame);
+        }
+    }
+
+    /// Send a message to all logged-in clients.
+    pub fn send(&self, cmd: u8, payload: &[u8]) {
+        for connection in self.shared.connections.lock().iter() {
+            if let Some(encoder) = connection.encoder.lock().as_mut() {
+                let _ = encoder.encode_slice(cmd, payload);
+            }
+        }
+    }
+
+    /// Write `data` to all logged-in clients, bypassing the Shannon encryption,
+    /// so the clients fail to decode the stream.
+    pub fn send_garbage(&self, data: &[u8]) {
+        for connection in self.shared.connections.lock().iter() {
+            if let Some(encoder) = connection.encoder.lock().as_mut() {
+                let _ = encoder.as_inner_mut().write_all(data);
+            }
+        }
+    }
+
+    /// Close all open connections.  Clients can connect again.
+    pub fn disconnect(&self) {
+        for connection in self.shared.connections.lock().iter() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Number of connections accepted so far.
+    pub fn connection_count(&self) -> usize {
+        self.shared.log.lock().connections
+    }
+
+    /// All requests received so far, in the order of arrival.
+    pub fn requests(&self) -> Vec<Received> {
+        self.shared.log.lock().requests.clone()
+    }
+
+    /// Wait until at least `count` connections were accepted.  Returns false
+    /// on timeout.
+    pub fn wait_for_connections(&self, count: usize, timeout: Duration) -> bool {
+        self.wait_until(timeout, |log| (log.connections >= count).then_some(()))
+            .is_some()
+    }
+
+    /// Wait for a request matching `predicate`, including the requests
+    /// received before the call.  Returns `None` on timeout.
+    pub fn wait_for_request(
+        &self,
+        timeout: Duration,
+        predicate: impl Fn(&Received) -> bool,
+    ) -> Option<Received> {
+        self.wait_until(timeout, |log| {
+            log.requests.iter().find(|req| predicate(req)).cloned()
+        })
+    }
+
+    fn wait_until<T>(&self, timeout: Duration, check: impl Fn(&Log) -> Option<T>) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut log = self.shared.log.lock();
+        loop {
+            if let Some(value) = check(&log) {
+                return Some(value);
+            }
+            if self
+                .shared
+                .changed
+                .wait_until(&mut log, deadline)
+                .timed_out()
+            {
+                return check(&log);
+            }
+        }
+    }
+}
+
+impl Drop for MockAp {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accepting thread, so it notices the flag.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.accepting_thread.take() {
+            let _ = thread.join();
+        }
+        self.disconnect();
+    }
+}
+
+impl Shared {
+    fn record(&self, connection: usize, request: MockRequest) {
+        self.log.lock().requests.push(Received {
+            connection,
+            request,
+        });
+        self.changed.notify_all();
+    }
+}
+
+fn accept_connections(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        if shared.stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("mock AP failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let connection = match stream.try_clone() {
+            Ok(clone) => {
+                let index = {
+                    let mut log = shared.log.lock();
+                    log.connections += 1;
+                    log.connections - 1
+                };
+                shared.changed.notify_all();
+                Arc::new(Connection {
+                    index,
+                    stream: clone,
+                    encoder: Mutex::new(None),
+                })
+            }
+            Err(err) => {
+                log::warn!("mock AP failed to clone stream: {}", err);
+                continue;
+            }
+        };
+        shared.connections.lock().push(connection.clone());
+        let shared = shared.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(&shared, &connection, stream) {
+                log::debug!("mock AP connection {} failed: {}", connection.index, err);
+            }
+            let _ = connection.stream.shutdown(Shutdown::Both);
+            shared
+                .connections
+                .lock()
+                .retain(|other| other.index != connection.index);
+        });
+    }
+}
+
+fn serve(shared: &Shared, connection: &Connection, mut stream: TcpStream) -> Result<(), Error> {
+    let hash_cash = shared.script.lock().hash_cash;
+    let (client_send_key, client_recv_key) = exchange_keys(&mut stream, hash_cash)?;
+    let mut encoder = ShannonEncoder::new(stream.try_clone()?, &client_recv_key);
+    let mut decoder = ShannonDecoder::new(stream, &client_send_key);
+
+    // Expect the login request first.
+    let msg = decoder.decode()?;
+    if msg.cmd != ShannonMsg::LOGIN {
+        return Err(Error::UnexpectedResponse);
+    }
+    let login: ClientResponseEncrypted = deserialize_protobuf(&msg.payload)?;
+    let credentials = login.login_credentials;
+    let username = credentials.username.clone();
+    shared.record(
+        connection.index,
+        MockRequest::Login {
+            username: credentials.username,
+            auth_type: credentials.typ,
+            auth_data: credentials.auth_data.unwrap_or_default(),
+        },
+    );
+    let (reply, country_code) = {
+        let mut script = shared.script.lock();
+        let reply = script.logins.pop_front();
+        (reply, script.country_code.clone())
+    };
+    let welcome = match reply.unwrap_or_else(|| LoginReply::Welcome(default_welcome(username))) {
+        LoginReply::Welcome(welcome) => serialize_protobuf(&welcome)?,
+        LoginReply::Failed(error_code) => {
+            let failed = APLoginFailed {
+                error_code,
+                ..APLoginFailed::default()
+            };
+            let failed = serialize_protobuf(&failed)?;
+            encoder.encode(ShannonMsg::new(ShannonMsg::AUTH_FAILURE, failed))?;
+            return Ok(());
+        }
+        LoginReply::Disconnect => {
+            return Ok(());
+        }
+        LoginReply::Silent => {
+            while decoder.decode().is_ok() {}
+            return Ok(());
+        }
+        LoginReply::Message(msg) => {
+            encoder.encode(msg)?;
+            return Ok(());
+        }
+    };
+    {
+        // Share the encoder before the client learns it is logged in, so the
+        // client can be pinged right after the login.
+        let mut shared_encoder = connection.encoder.lock();
+        let encoder = shared_encoder.insert(encoder);
+        encoder.encode(ShannonMsg::new(ShannonMsg::AP_WELCOME, welcome))?;
+        if let Some(country_code) = country_code {
+            encoder.encode(ShannonMsg::new(
+                ShannonMsg::COUNTRY_CODE,
+                country_code.into_bytes(),
+            ))?;
+        }
+    }
+
+    loop {
+        let msg = decoder.decode()?;
+        handle_msg(shared, connection, msg)?;
+    }
+}
+
+/// Server side of the key exchange.  Returns the sending and receiving keys of
+/// the client.
+fn exchange_keys(
+    stream: &mut TcpStream,
+    hash_cash: Option<(i32, i32)>,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    // The hello packet starts with the protocol version, and its size counts
+    // the version as well.
+    let mut hello_packet = vec![0_u8; 6];
+    stream.read_exact(&mut hello_packet)?;
+    let size = u32::from_be_bytes(hello_packet[2..6].try_into().unwrap()) as usize;
+    if size < hello_packet.len() {
+        return Err(Error::UnexpectedResponse);
+    }
+    hello_packet.resize(size, 0);
+    stream.read_exact(&mut hello_packet[6..])?;
+    let hello: ClientHello = deserialize_protobuf(&hello_packet[6..])?;
+    let client_key = hello
+        .login_crypto_hello
+        .diffie_hellman
+        .ok_or(Error::UnexpectedResponse)?
+        .gc;
+
+    let local_keys = DHLocalKeys::random();
+    let hash_cash = hash_cash.map(|(length, target)| PoWHashCashChallenge {
+        prefix: Some(rand::random::<[u8; 16]>().to_vec()),
+        length: Some(length),
+        target: Some(target),
+    });
+    let apresp = APResponseMessage {
+        challenge: Some(APChallenge {
+            login_crypto_challenge: LoginCryptoChallengeUnion {
+                diffie_hellman: Some(LoginCryptoDiffieHellmanChallenge {
+                    gs: local_keys.public_key(),
+                    server_signature_key: 1,
+                    gs_signature: Vec::new(),
+                }),
+            },
+            pow_challenge: PoWChallengeUnion {
+                hash_cash: hash_cash.clone(),
+            },
+            server_nonce: rand::random::<[u8; 16]>().to_vec(),
+            ..APChallenge::default()
+        }),
+        ..APResponseMessage::default()
+    };
+    let apresp_packet = make_packet(&[], &serialize_protobuf(&apresp)?);
+    stream.write_all(&apresp_packet)?;
+
+    // Check the challenge response of the client.  Like the AP, we just close
+    // the connection if it is wrong.
+    let (challenge, send_key, recv_key) = compute_keys(
+        &local_keys.shared_secret(&client_key),
+        &hello_packet,
+        &apresp_packet,
+    );
+    let response_packet = read_packet(stream)?;
+    let response: ClientResponsePlaintext = deserialize_protobuf(&response_packet[4..])?;
+    let hmac = response
+        .login_crypto_response
+        .diffie_hellman
+        .map(|dh| dh.hmac);
+    if hmac != Some(challenge) {
+        log::warn!("mock AP received wrong challenge response");
+        return Err(Error::UnexpectedResponse);
+    }
+    if let Some(hash_cash) = hash_cash {
+        let prefix = hash_cash.prefix.unwrap_or_default();
+        let target = hash_cash.target.unwrap_or_default() as u32;
+        let solved = response
+            .pow_response
+            .hash_cash
+            .is_some_and(|pow| hashcash::is_solution(&prefix, &pow.hash_suffix, target));
+        if !solved {
+            log::warn!("mock AP received wrong hashcash solution");
+            return Err(Error::UnexpectedResponse);
+        }
+    }
+    Ok((send_key, recv_key))
+}
+
+fn default_welcome(username: Option<String>) -> APWelcome {
+    APWelcome {
+        canonical_username: username.unwrap_or_else(|| "mock-user".to_string()),
+        reusable_auth_credentials_type:
+            AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+        reusable_auth_credentials: b"mock-reusable-credentials".to_vec(),
+        ..APWelcome::default()
+    }
+}
+
+fn handle_msg(shared: &Shared, connection: &Connection, msg: ShannonMsg) -> Result<(), Error> {
+    match msg.cmd {
+        ShannonMsg::MERCURY_REQ | ShannonMsg::MERCURY_SUB | ShannonMsg::MERCURY_UNSUB => {
+            let request = Msg::decode(msg.payload)?;
+            let (header_part, payload) = request
+                .parts
+                .split_first()
+                .ok_or(Error::UnexpectedResponse)?;
+            let header: Header = deserialize_protobuf(header_part)?;
+            let uri = header.uri.unwrap_or_default();
+            shared.record(
+                connection.index,
+                MockRequest::Mercury {
+                    method: header.method.unwrap_or_default(),
+                    uri: uri.clone(),
+                    payload: payload.iter().map(|part| part.to_vec()).collect(),
+                },
+            );
+            let (reply, frame_size) = {
+                let script = shared.script.lock();
+                let reply = script.mercury.get(&uri).cloned().unwrap_or_else(|| {
+                    if msg.cmd == ShannonMsg::MERCURY_REQ {
+                        MercuryReply::status(404)
+                    } else {
+                        MercuryReply::ok(Vec::new())
+                    }
+                });
+                (reply, script.mercury_frame_size)
+            };
+            if let MercuryReply::Response {
+                status_code,
+                payload,
+            } = reply
+            {
+                let header = Header {
+                    uri: Some(uri),
+                    status_code: Some(status_code),
+                    ..Header::default()
+                };
+                let header = serialize_protobuf(&header)?;
+                let parts: Vec<&[u8]> = std::iter::once(header.as_slice())
+                    .chain(payload.iter().map(Vec::as_slice))
+                    .collect();
+                for frame in mercury_frames(request.seq, &parts, frame_size) {
+                    reply_with(connection, ShannonMsg::new(msg.cmd, frame))?;
+                }
+            }
+        }
+        ShannonMsg::REQUEST_KEY => {
+            // File ID, track ID, sequence number and two zero bytes.
+            let file = msg
+                .payload
+                .get(..20)
+                .and_then(FileId::from_raw)
+                .ok_or(Error::UnexpectedResponse)?;
+            let seq = msg.payload.get(36..40).ok_or(Error::UnexpectedResponse)?;
+            shared.record(connection.index, MockRequest::AudioKey { file });
+            let reply = shared
+                .script
+                .lock()
+                .audio_keys
+                .get(&file)
+                .cloned()
+                .unwrap_or(AudioKeyReply::Error);
+            match reply {
+                AudioKeyReply::Key(key) => {
+                    let payload = [seq, &key.0].concat();
+                    reply_with(connection, ShannonMsg::new(ShannonMsg::AES_KEY, payload))?;
+                }
+                AudioKeyReply::Error => {
+                    let payload = [seq, &AUDIO_KEY_ERROR_CODE.to_be_bytes()].concat();
+                    reply_with(
+                        connection,
+                        ShannonMsg::new(ShannonMsg::AES_KEY_ERROR, payload),
+                    )?;
+                }
+                AudioKeyReply::Silent => {}
+            }
+        }
+        ShannonMsg::PONG => {
+            shared.record(connection.index, MockRequest::Pong);
+            reply_with(
+                connection,
+                ShannonMsg::new(ShannonMsg::PONG_ACK, Vec::new()),
+            )?;
+        }
+        cmd => {
+            shared.record(
+                connection.index,
+                MockRequest::Other {
+                    cmd,
+                    payload: msg.payload.to_vec(),
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+fn reply_with(connection: &Connection, msg: ShannonMsg) -> Result<(), Error> {
+    if let Some(encoder) = connection.encoder.lock().as_mut() {
+        encoder.encode(msg)?;
+    }
+    Ok(())
+}
+
+/// Pack the parts of a Mercury message into frames of at most `frame_size`
+/// bytes.  Parts not fitting into the rest of a frame are split, and continue
+/// in the next one.
+fn mercury_frames(seq: u64, parts: &[&[u8]], frame_size: usize) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut frame_parts: Vec<&[u8]> = Vec::new();
+    let mut free = frame_size - MERCURY_HEADER_SIZE;
+    for part in parts {
+        let mut rest = *part;
+        loop {
+            // Keep room for the length of the next part, so a frame never ends
+            // right on a part boundary.  The last part of a partial frame is
+            // always continued in the next one.
+            let fits = free.saturating_sub(2 * MERCURY_PART_LEN_SIZE);
+            if rest.len() <= fits {
+                frame_parts.push(rest);
+                free -= MERCURY_PART_LEN_SIZE + rest.len();
+                break;
+            }
+            let (head, tail) = rest.split_at(fits);
+            frame_parts.push(head);
+            frames.push(Msg::encode(seq, Msg::PARTIAL, &frame_parts));
+            frame_parts.clear();
+            free = frame_size - MERCURY_HEADER_SIZE;
+            rest = tail;
+        }
+    }
+    frames.push(Msg::encode(seq, Msg::FINAL, &frame_parts));
+    frames
+}
@@ -1,23 +1,33 @@
+#[cfg(feature = "async")]
+pub mod async_transport;
 pub mod diffie_hellman;
+pub mod hashcash;
+#[cfg(feature = "test-support")]
+pub mod mock_ap;
+pub mod proxy;
 pub mod shannon_codec;
+pub mod tcp;
 
+use base64::{prelude::BASE64_STANDARD, Engine};
 use byteorder::{ReadBytesExt, BE};
 use hmac::{Hmac, Mac};
 use sha1::Sha1;
 use std::{
     io::{self, Read, Write},
     net::{TcpStream, ToSocketAddrs},
+    time::Instant,
 };
 
 use diffie_hellman::DHLocalKeys;
-use psst_protocol::authentication::AuthenticationType;
+use proxy::connect_through_proxy;
+use psst_protocol::authentication::{APWelcome, AuthenticationType};
 use serde::{Deserialize, Serialize};
 use shannon_codec::{ShannonDecoder, ShannonEncoder, ShannonMsg};
-use socks::Socks5Stream;
-use url::Url;
+use tcp::{connect_racing, resolve_before};
 
 use crate::{
     error::Error,
+    oauth::OAuthToken,
     util::{
         default_ureq_agent_builder, deserialize_protobuf, serialize_protobuf, NET_CONNECT_TIMEOUT,
         NET_IO_TIMEOUT,
@@ -25,12 +35,15 @@ use crate::{
 };
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(from = "SerializedCredentials")]
+#[serde(try_from = "SerializedCredentials")]
 #[serde(into = "SerializedCredentials")]
 pub struct Credentials {
     pub username: Option<String>,
     pub auth_data: Vec<u8>,
     pub auth_type: AuthenticationType,
+    /// OAuth refresh token, used to log in again without user interaction if
+    /// the AP rejects these credentials.
+    pub refresh_token: Option<String>,
 }
 
 // Device ID used for authentication message.
@@ -48,6 +61,7 @@ impl Credentials {
             username: Some(username),
             auth_type: AuthenticationType::AUTHENTICATION_USER_PASS,
             auth_data: password.into_bytes(),
+            refresh_token: None,
         }
     }
 
@@ -56,34 +70,99 @@ impl Credentials {
             username: None,
             auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
             auth_data: token.into_bytes(),
+            refresh_token: None,
+        }
+    }
+
+    /// Log in with the access token, and keep the refresh token for later.
+    pub fn from_oauth_token(token: OAuthToken) -> Self {
+        Self {
+            refresh_token: token.refresh_token,
+            ..Self::from_access_token(token.access_token)
+        }
+    }
+
+    /// Reusable credentials the AP hands out after a successful login.
+    pub fn from_welcome(welcome: &APWelcome) -> Self {
+        Self {
+            username: Some(welcome.canonical_username.clone()),
+            auth_data: welcome.reusable_auth_credentials.clone(),
+            auth_type: welcome.reusable_auth_credentials_type,
+            refresh_token: None,
         }
     }
 }
 
+// Version of the serialized `Credentials` format we write.
+const CREDENTIALS_FORMAT_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize)]
-struct SerializedCredentials {
-    username: String,
-    auth_data: String,
-    auth_type: i32,
+#[serde(untagged)]
+enum SerializedCredentials {
+    Versioned {
+        version: u32,
+        #[serde(default)]
+        username: Option<String>,
+        auth_type: i32,
+        /// Base64-encoded, as reusable credentials are binary.
+        auth_data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        refresh_token: Option<String>,
+    },
+    /// Layout of older versions, keeping the auth data as a UTF-8 string and a
+    /// missing username as an empty string.
+    Legacy {
+        username: String,
+        auth_data: String,
+        auth_type: i32,
+    },
 }
 
-impl From<SerializedCredentials> for Credentials {
-    fn from(value: SerializedCredentials) -> Self {
-        Self {
-            username: Some(value.username),
-            auth_data: value.auth_data.into_bytes(),
-            auth_type: value.auth_type.into(),
+impl TryFrom<SerializedCredentials> for Credentials {
+    type Error = String;
+
+    fn try_from(value: SerializedCredentials) -> Result<Self, Self::Error> {
+        match value {
+            SerializedCredentials::Versioned {
+                version: CREDENTIALS_FORMAT_VERSION,
+                username,
+                auth_type,
+                auth_data,
+                refresh_token,
+            } => Ok(Self {
+                username,
+                auth_data: BASE64_STANDARD
+                    .decode(auth_data)
+                    .map_err(|err| format!("invalid credentials auth data: {}", err))?,
+                auth_type: auth_type.into(),
+                refresh_token,
+            }),
+            SerializedCredentials::Versioned { version, .. } => Err(format!(
+                "unsupported credentials format version {}",
+                version
+            )),
+            SerializedCredentials::Legacy {
+                username,
+                auth_data,
+                auth_type,
+            } => Ok(Self {
+                username: Some(username).filter(|username| !username.is_empty()),
+                auth_data: auth_data.into_bytes(),
+                auth_type: auth_type.into(),
+                refresh_token: None,
+            }),
         }
     }
 }
 
 impl From<Credentials> for SerializedCredentials {
     fn from(value: Credentials) -> Self {
-        Self {
-            username: value.username.unwrap_or_default(),
-            auth_data: String::from_utf8(value.auth_data)
-                .expect("Invalid UTF-8 in serialized credentials"),
+        Self::Versioned {
+            version: CREDENTIALS_FORMAT_VERSION,
+            username: value.username,
             auth_type: value.auth_type as _,
+            auth_data: BASE64_STANDARD.encode(value.auth_data),
+            refresh_token: value.refresh_token,
         }
     }
 }
@@ -95,17 +174,20 @@ pub struct Transport {
 }
 
 impl Transport {
-    pub fn resolve_ap_with_fallback(proxy_url: Option<&str>) -> String {
-        match Self::resolve_ap(proxy_url) {
-            Ok(ap) => ap,
-            Err(err) => {
-                log::error!("using AP fallback, error while resolving: {:?}", err);
-                AP_FALLBACK.into()
-            }
+    /// Resolve the list of access points, in the order they should be tried.
+    /// `AP_FALLBACK` is always included as the last entry.
+    pub fn resolve_ap_list_with_fallback(proxy_url: Option<&str>) -> Vec<String> {
+        let mut ap_list = Self::resolve_ap_list(proxy_url).unwrap_or_else(|err| {
+            log::error!("using AP fallback, error while resolving: {:?}", err);
+            Vec::new()
+        });
+        if !ap_list.iter().any(|ap| ap == AP_FALLBACK) {
+            ap_list.push(AP_FALLBACK.into());
         }
+        ap_list
     }
 
-    pub fn resolve_ap(proxy_url: Option<&str>) -> Result<String, Error> {
+    pub fn resolve_ap_list(proxy_url: Option<&str>) -> Result<Vec<String>, Error> {
         #[derive(Clone, Debug, Deserialize)]
         struct APResolveData {
             ap_list: Vec<String>,
@@ -113,117 +195,67 @@ impl Transport {
 
         let agent = default_ureq_agent_builder(proxy_url)?.build();
         let data: APResolveData = agent.get(AP_RESOLVE_ENDPOINT).call()?.into_json()?;
-        data.ap_list
-            .into_iter()
-            .next()
-            .ok_or(Error::UnexpectedResponse)
+        if data.ap_list.is_empty() {
+            Err(Error::UnexpectedResponse)
+        } else {
+            Ok(data.ap_list)
+        }
     }
 
     pub fn connect(ap: &str, proxy_url: Option<&str>) -> Result<Self, Error> {
+        Self::connect_before(ap, proxy_url, Instant::now() + NET_CONNECT_TIMEOUT)
+    }
+
+    /// Like `connect`, but resolving and connecting to the AP (or the proxy)
+    /// needs to finish before `deadline`.  Lets the callers trying several APs
+    /// share a single deadline.
+    pub fn connect_before(
+        ap: &str,
+        proxy_url: Option<&str>,
+        deadline: Instant,
+    ) -> Result<Self, Error> {
         log::trace!("connecting to: {:?} with proxy: {:?}", ap, proxy_url);
         let stream = if let Some(url) = proxy_url {
-            Self::stream_through_proxy(ap, url)?
+            connect_through_proxy(ap, url, deadline)?
         } else {
-            Self::stream_without_proxy(ap)?
+            let ap = ap.to_string();
+            let addrs = resolve_before(move || Ok(ap.to_socket_addrs()?.collect()), deadline)?;
+            connect_racing(addrs, deadline)?
         };
         if let Err(err) = stream.set_write_timeout(Some(NET_IO_TIMEOUT)) {
             log::warn!("failed to set TCP write timeout: {:?}", err);
         }
         log::trace!("connected");
-        Self::exchange_keys(stream)
-    }
-
-    fn stream_without_proxy(ap: &str) -> Result<TcpStream, io::Error> {
-        let mut last_err = None;
-        for addr in ap.to_socket_addrs()? {
-            match TcpStream::connect_timeout(&addr, NET_CONNECT_TIMEOUT) {
-                Ok(stream) => {
-                    return Ok(stream);
-                }
-                Err(err) => {
-                    last_err.replace(err);
-                }
-            }
-        }
-        Err(last_err.unwrap_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "could not resolve to any addresses",
-            )
-        }))
-    }
-
-    fn stream_through_proxy(ap: &str, url: &str) -> Result<TcpStream, Error> {
-        match Url::parse(url) {
-            Ok(url) if url.scheme() == "socks" || url.scheme() == "socks5" => {
-                // Currently we only support SOCKS5 proxies.
-                Self::stream_through_socks5_proxy(ap, &url)
-            }
-            _ => {
-                // Proxy URL failed to parse or has unsupported scheme.
-                Err(Error::ProxyUrlInvalid)
-            }
-        }
+        Self::exchange_keys(stream, deadline)
     }
 
-    fn stream_through_socks5_proxy(ap: &str, url: &Url) -> Result<TcpStream, Error> {
-        let addrs = url.socket_addrs(|| None)?;
-        let username = url.username();
-        let password = url.password().unwrap_or("");
-        // TODO: `socks` crate does not support connection timeouts.
-        let proxy = if username.is_empty() {
-            Socks5Stream::connect(&addrs[..], ap)?
-        } else {
-            Socks5Stream::connect_with_password(&addrs[..], ap, username, password)?
-        };
-        Ok(proxy.into_inner())
-    }
-
-    pub fn exchange_keys(mut stream: TcpStream) -> Result<Self, Error> {
-        use psst_protocol::keyexchange::APResponseMessage;
-
-        let local_keys = DHLocalKeys::random();
+    /// Run the key exchange over `stream`.  A proof-of-work challenge of the
+    /// AP needs to be solved before `deadline`.
+    pub fn exchange_keys(mut stream: TcpStream, deadline: Instant) -> Result<Self, Error> {
+        // Do not wait forever for a half-open connection to answer.
+        stream.set_read_timeout(Some(NET_IO_TIMEOUT))?;
 
         // Start by sending the hello message with our public key and nonce.
         log::trace!("sending client hello");
-        let client_nonce: [u8; 16] = rand::random();
-        let hello = client_hello(local_keys.public_key(), client_nonce.into());
-        let hello_packet = make_packet(&[0, 4], &hello);
-        stream.write_all(&hello_packet)?;
+        let handshake = Handshake::start();
+        stream.write_all(&handshake.hello_packet)?;
         log::trace!("sent client hello");
 
-        // Wait for the response packet with the remote public key.  Note that we are
-        // keeping both the hello packet and the response packet for later (they get
-        // hashed together with the shared secret to make a key pair).
+        // Wait for the response packet with the remote public key.
         log::trace!("waiting for AP response");
         let apresp_packet = read_packet(&mut stream)?;
-        let apresp: APResponseMessage = deserialize_protobuf(&apresp_packet[4..])?;
         log::trace!("received AP response");
 
-        // Compute the challenge response and the sending/receiving keys.
-        let remote_key = &apresp
-            .challenge
-            .expect("Missing data")
-            .login_crypto_challenge
-            .diffie_hellman
-            .expect("Missing data")
-            .gs;
-        let (challenge, send_key, recv_key) = compute_keys(
-            &local_keys.shared_secret(remote_key),
-            &hello_packet,
-            &apresp_packet,
-        );
-
         // Respond with the computed HMAC and finish the handshake.
         log::trace!("sending client response");
-        let response = client_response_plaintext(challenge);
-        let response_packet = make_packet(&[], &response);
-        stream.write_all(&response_packet)?;
+        let keys = handshake.finish(&apresp_packet, deadline)?;
+        stream.write_all(&keys.response_packet)?;
         log::trace!("sent client response");
+        stream.set_read_timeout(None)?;
 
         // Use the derived keys to make a codec, wrapping the TCP stream.
-        let encoder = ShannonEncoder::new(stream.try_clone()?, &send_key);
-        let decoder = ShannonDecoder::new(stream.try_clone()?, &recv_key);
+        let encoder = ShannonEncoder::new(stream.try_clone()?, &keys.send_key);
+        let decoder = ShannonDecoder::new(stream.try_clone()?, &keys.recv_key);
 
         Ok(Self {
             stream,
@@ -232,43 +264,124 @@ impl Transport {
         })
     }
 
-    pub fn authenticate(&mut self, credentials: Credentials) -> Result<Credentials, Error> {
-        use psst_protocol::{authentication::APWelcome, keyexchange::APLoginFailed};
-
+    /// Log in with `credentials`.  The returned welcome message carries the
+    /// account details and reusable credentials, see `Credentials::from_welcome`.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<APWelcome, Error> {
         // Send a login request with the client credentials.
         let request = client_response_encrypted(credentials);
         self.encoder.encode(request)?;
 
         // Expect an immediate response with the authentication result.
+        self.stream.set_read_timeout(Some(NET_IO_TIMEOUT))?;
         let response = self.decoder.decode()?;
+        self.stream.set_read_timeout(None)?;
+        parse_auth_response(response)
+    }
+}
+
+/// Client side of the key exchange, apart from the I/O, so it can be driven by
+/// both the blocking and the async transport.
+struct Handshake {
+    local_keys: DHLocalKeys,
+    hello_packet: Vec<u8>,
+}
+
+/// Outcome of a successful `Handshake`.
+struct HandshakeKeys {
+    response_packet: Vec<u8>,
+    send_key: Vec<u8>,
+    recv_key: Vec<u8>,
+}
+
+impl Handshake {
+    fn start() -> Self {
+        let local_keys = DHLocalKeys::random();
+        let client_nonce: [u8; 16] = rand::random();
+        let hello = client_hello(local_keys.public_key(), client_nonce.into());
+        let hello_packet = make_packet(&[0, 4], &hello);
+        Self {
+            local_keys,
+            hello_packet,
+        }
+    }
 
-        match response.cmd {
-            ShannonMsg::AP_WELCOME => {
-                let welcome_data: APWelcome =
-                    deserialize_protobuf(&response.payload).expect("Missing data");
-                Ok(Credentials {
-                    username: Some(welcome_data.canonical_username),
-                    auth_data: welcome_data.reusable_auth_credentials,
-                    auth_type: welcome_data.reusable_auth_credentials_type,
-                })
-            }
-            ShannonMsg::AUTH_FAILURE => {
-                let error_data: APLoginFailed =
-                    deserialize_protobuf(&response.payload).expect("Missing data");
-                Err(Error::AuthFailed {
-                    code: error_data.error_code as _,
-                })
-            }
-            _ => {
-                unreachable!("unexpected message");
-            }
+    /// Compute the challenge response and the sending/receiving keys from the
+    /// AP response packet.  Note that both the hello packet and the response
+    /// packet get hashed together with the shared secret to make the key pair.
+    /// Gives up solving the proof-of-work challenge at `deadline`.
+    fn finish(self, apresp_packet: &[u8], deadline: Instant) -> Result<HandshakeKeys, Error> {
+        use psst_protocol::keyexchange::APResponseMessage;
+
+        let payload = apresp_packet.get(4..).ok_or(Error::UnexpectedResponse)?;
+        let apresp: APResponseMessage = deserialize_protobuf(payload)?;
+        let ap_challenge = apresp.challenge.ok_or(Error::UnexpectedResponse)?;
+        let remote_key = &ap_challenge
+            .login_crypto_challenge
+            .diffie_hellman
+            .ok_or(Error::UnexpectedResponse)?
+            .gs;
+        let (challenge, send_key, recv_key) = compute_keys(
+            &self.local_keys.shared_secret(remote_key),
+            &self.hello_packet,
+            apresp_packet,
+        );
+
+        // Solve the proof-of-work challenge, if the AP asks for it.
+        let pow_response = match ap_challenge.pow_challenge.hash_cash {
+            Some(hash_cash) => Some(solve_hash_cash(&hash_cash, deadline)?),
+            None => None,
+        };
+
+        let response = client_response_plaintext(challenge, pow_response);
+        Ok(HandshakeKeys {
+            response_packet: make_packet(&[], &response),
+            send_key,
+            recv_key,
+        })
+    }
+}
+
+fn parse_auth_response(response: ShannonMsg) -> Result<APWelcome, Error> {
+    use psst_protocol::keyexchange::APLoginFailed;
+
+    match response.cmd {
+        ShannonMsg::AP_WELCOME => {
+            let welcome_data: APWelcome = deserialize_protobuf(&response.payload)?;
+            Ok(welcome_data)
         }
+        ShannonMsg::AUTH_FAILURE => {
+            let error_data: APLoginFailed = deserialize_protobuf(&response.payload)?;
+            Err(Error::AuthFailed {
+                code: error_data.error_code as _,
+            })
+        }
+        cmd => {
+            log::error!("unexpected login response: {:?}", cmd);
+            Err(Error::UnexpectedResponse)
+        }
+    }
+}
+
+// Largest handshake packet accepted from the AP.  The key exchange response
+// takes only a few hundred bytes.
+const MAX_HANDSHAKE_PACKET_SIZE: usize = 64 * 1024;
+
+/// Check the size prefix of a handshake packet.  The size includes the 4
+/// bytes of the prefix itself.
+fn handshake_packet_size(size: u32) -> io::Result<usize> {
+    let size = size as usize;
+    if !(4..=MAX_HANDSHAKE_PACKET_SIZE).contains(&size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid handshake packet size: {}", size),
+        ));
     }
+    Ok(size)
 }
 
 fn read_packet(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
     let size = stream.read_u32::<BE>()?;
-    let mut buf = vec![0_u8; size as usize];
+    let mut buf = vec![0_u8; handshake_packet_size(size)?];
     let (size_buf, data_buf) = buf.split_at_mut(4);
     size_buf.copy_from_slice(&size.to_be_bytes());
     stream.read_exact(data_buf)?;
@@ -297,7 +410,7 @@ fn client_hello(public_key: Vec<u8>, nonce: Vec<u8>) -> Vec<u8> {
         },
         cryptosuites_supported: vec![Cryptosuite::CRYPTO_SUITE_SHANNON],
         fingerprints_supported: vec![],
-        powschemes_supported: vec![],
+        powschemes_supported: vec![Powscheme::POW_HASH_CASH],
         login_crypto_hello: LoginCryptoHelloUnion {
             diffie_hellman: Some(LoginCryptoDiffieHellmanHello {
                 gc: public_key,
@@ -312,14 +425,49 @@ fn client_hello(public_key: Vec<u8>, nonce: Vec<u8>) -> Vec<u8> {
     serialize_protobuf(&hello).expect("Failed to serialize")
 }
 
-fn client_response_plaintext(challenge: Vec<u8>) -> Vec<u8> {
+fn solve_hash_cash(
+    challenge: &psst_protocol::keyexchange::PoWHashCashChallenge,
+    deadline: Instant,
+) -> Result<psst_protocol::keyexchange::PoWHashCashResponse, Error> {
+    use psst_protocol::keyexchange::PoWHashCashResponse;
+
+    let prefix = challenge.prefix.as_deref().unwrap_or_default();
+    let length = challenge.length.unwrap_or_default();
+    let target = challenge.target.unwrap_or_default();
+    log::trace!("solving hashcash, length: {}, target: {}", length, target);
+    let hash_suffix = hashcash::solve(
+        prefix,
+        length.try_into().map_err(|_| Error::UnexpectedResponse)?,
+        target.try_into().map_err(|_| Error::UnexpectedResponse)?,
+        deadline,
+    );
+    let hash_suffix = match hash_suffix {
+        Some(hash_suffix) => hash_suffix,
+        None if Instant::now() >= deadline => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out solving the hashcash challenge",
+            )
+            .into());
+        }
+        None => return Err(Error::UnexpectedResponse),
+    };
+    Ok(PoWHashCashResponse { hash_suffix })
+}
+
+fn client_response_plaintext(
+    challenge: Vec<u8>,
+    pow_response: Option<psst_protocol::keyexchange::PoWHashCashResponse>,
+) -> Vec<u8> {
     use psst_protocol::keyexchange::*;
 
     let response = ClientResponsePlaintext {
         login_crypto_response: LoginCryptoResponseUnion {
             diffie_hellman: Some(LoginCryptoDiffieHellmanResponse { hmac: challenge }),
         },
-        pow_response: PoWResponseUnion::default(),
+        pow_response: PoWResponseUnion {
+            hash_cash: pow_response,
+        },
         crypto_response: CryptoResponseUnion::default(),
     };
 
@@ -373,3 +521,83 @@ fn client_response_encrypted(credentials: Credentials) -> ShannonMsg {
     let buf = serialize_protobuf(&response).expect("Failed to serialize");
     ShannonMsg::new(ShannonMsg::LOGIN, buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_handshake_packet_sizes() {
+        assert_eq!(handshake_packet_size(4).unwrap(), 4);
+        assert_eq!(handshake_packet_size(300).unwrap(), 300);
+        for size in [0, 3, MAX_HANDSHAKE_PACKET_SIZE as u32 + 1, u32::MAX] {
+            let err = handshake_packet_size(size).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_ap_responses_without_challenge() {
+        use psst_protocol::keyexchange::{APChallenge, APResponseMessage};
+
+        let finish = |packet: &[u8]| Handshake::start().finish(packet, Instant::now());
+        assert!(matches!(finish(&[0, 0]), Err(Error::UnexpectedResponse)));
+        let response = APResponseMessage::default();
+        let packet = make_packet(&[], &serialize_protobuf(&response).unwrap());
+        assert!(matches!(finish(&packet), Err(Error::UnexpectedResponse)));
+        let response = APResponseMessage {
+            challenge: Some(APChallenge::default()),
+            ..APResponseMessage::default()
+        };
+        let packet = make_packet(&[], &serialize_protobuf(&response).unwrap());
+        assert!(matches!(finish(&packet), Err(Error::UnexpectedResponse)));
+    }
+
+    #[test]
+    fn reads_legacy_credentials() {
+        let json = r#"{"username":"user","auth_data":"secret","auth_type":1}"#;
+        let credentials: Credentials = serde_json::from_str(json).unwrap();
+        assert_eq!(credentials.username.as_deref(), Some("user"));
+        assert_eq!(credentials.auth_data, b"secret");
+        assert_eq!(
+            credentials.auth_type,
+            AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS
+        );
+        assert_eq!(credentials.refresh_token, None);
+
+        let json = r#"{"username":"","auth_data":"token","auth_type":3}"#;
+        let credentials: Credentials = serde_json::from_str(json).unwrap();
+        assert_eq!(credentials.username, None);
+    }
+
+    #[test]
+    fn round_trips_binary_auth_data() {
+        let credentials = Credentials {
+            username: Some("user".to_string()),
+            auth_data: vec![0x00, 0xff, 0xc3, 0x28, 0x80],
+            auth_type: AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+            refresh_token: Some("refresh".to_string()),
+        };
+        let json = serde_json::to_value(&credentials).unwrap();
+        assert_eq!(json["version"], CREDENTIALS_FORMAT_VERSION);
+        assert_eq!(json["auth_data"], "AP/DKIA=");
+
+        let read: Credentials = serde_json::from_value(json).unwrap();
+        assert_eq!(read.username, credentials.username);
+        assert_eq!(read.auth_data, credentials.auth_data);
+        assert_eq!(read.auth_type, credentials.auth_type);
+        assert_eq!(read.refresh_token, credentials.refresh_token);
+    }
+
+    #[test]
+    fn rejects_unsupported_credentials_versions() {
+        let json = r#"{"version":2,"username":"user","auth_data":"c2VjcmV0","auth_type":1}"#;
+        let err = serde_json::from_str::<Credentials>(json).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unsupported credentials format version 2"));
+
+        let json = r#"{"version":1,"auth_data":"not base64!","auth_type":1}"#;
+        assert!(serde_json::from_str::<Credentials>(json).is_err());
+    }
+}
@@ -1,11 +1,15 @@
 use std::{convert::TryInto, io};
 
+use bytes::{Bytes, BytesMut};
 use shannon::Shannon;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug)]
 pub struct ShannonMsg {
     pub cmd: u8,
-    pub payload: Vec<u8>,
+    /// Decoded payloads share the buffer of the decoder, see `ShannonDecoder`.
+    pub payload: Bytes,
 }
 
 impl ShannonMsg {
@@ -34,7 +38,7 @@ impl ShannonMsg {
     pub const MERCURY_UNSUB: u8 = 0xb4;
     pub const MERCURY_PUB: u8 = 0xb5;
 
-    pub fn new(cmd: u8, payload: impl Into<Vec<u8>>) -> Self {
+    pub fn new(cmd: u8, payload: impl Into<Bytes>) -> Self {
         Self {
             cmd,
             payload: payload.into(),
@@ -45,43 +49,138 @@ impl ShannonMsg {
 const MAC_SIZE: usize = 4;
 const HEADER_SIZE: usize = 3;
 
-pub struct ShannonEncoder<T> {
-    inner: T,
+// Size of the buffer the decoder carves the payloads from.  Fits a whole frame
+// of the maximal size, and many of the usual, small ones.
+const PAYLOAD_POOL_SIZE: usize = 64 * 1024;
+
+// Payloads smaller than this are copied out of the pool buffer, so holding on
+// to them does not keep the whole buffer alive.
+const PAYLOAD_COPY_THRESHOLD: usize = 4 * 1024;
+
+/// Cipher state of one direction of the connection, shared by the blocking and
+/// the async codec.
+struct ShannonCipher {
     nonce: u32,
     cipher: Shannon,
 }
 
-impl<T> ShannonEncoder<T>
-where
-    T: io::Write,
-{
-    pub fn new(inner: T, send_key: &[u8]) -> Self {
+impl ShannonCipher {
+    fn new(key: &[u8]) -> Self {
         Self {
-            inner,
             nonce: 0,
-            cipher: Shannon::new(send_key),
+            cipher: Shannon::new(key),
         }
     }
 
-    pub fn encode(&mut self, item: ShannonMsg) -> io::Result<()> {
-        // Buffer up the whole message.
-        let mut buf = Vec::with_capacity(HEADER_SIZE + item.payload.len() + MAC_SIZE);
-        let len_u16: u16 = item.payload.len().try_into().unwrap();
-        buf.push(item.cmd);
-        buf.extend(len_u16.to_be_bytes());
-        buf.extend(item.payload);
-
-        // Seed the cipher, rotate the nonce, and encrypt the header and payload.
+    /// Seed the cipher and rotate the nonce.
+    fn next_nonce(&mut self) {
         self.cipher.nonce_u32(self.nonce);
         self.nonce += 1;
-        self.cipher.encrypt(&mut buf);
+    }
+
+    /// Encrypt a message into a complete frame in `buf`, including the MAC.
+    /// The previous contents of `buf` are dropped, but its capacity is reused.
+    fn seal(&mut self, cmd: u8, payload: &[u8], buf: &mut Vec<u8>) {
+        let len_u16: u16 = payload.len().try_into().unwrap();
+        buf.clear();
+        buf.reserve(HEADER_SIZE + payload.len() + MAC_SIZE);
+        buf.push(cmd);
+        buf.extend(len_u16.to_be_bytes());
+        buf.extend(payload);
+
+        // Encrypt the header and payload.
+        self.next_nonce();
+        self.cipher.encrypt(buf);
 
         // Compute the MAC and append it.
         let mut mac = [0_u8; MAC_SIZE];
         self.cipher.finish(&mut mac);
         buf.extend(mac);
+    }
+
+    /// Decrypt the header of the next frame, and return the command and the
+    /// payload size.
+    fn open_header(&mut self, header: &mut [u8; HEADER_SIZE]) -> (u8, usize) {
+        self.next_nonce();
+        self.cipher.decrypt(header);
+        let cmd = header[0];
+        let size = u16::from_be_bytes([header[1], header[2]]) as usize;
+        (cmd, size)
+    }
+
+    fn open_payload(&mut self, payload: &mut [u8]) {
+        self.cipher.decrypt(payload);
+    }
+
+    fn check_mac(&mut self, mac: &[u8; MAC_SIZE]) -> io::Result<()> {
+        self.cipher.check_mac(mac)
+    }
+}
+
+/// Buffer the decoded payloads are split off from.  The payloads keep sharing
+/// its allocation, which gets reused as soon as all of them are dropped.
+/// Otherwise, a new buffer is allocated once the free space runs out.  Small
+/// payloads are copied instead, see `PAYLOAD_COPY_THRESHOLD`.
+struct PayloadPool {
+    buf: BytesMut,
+}
+
+impl PayloadPool {
+    fn new() -> Self {
+        Self {
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Return a buffer of `size` bytes to read the next payload into.
+    fn prepare(&mut self, size: usize) -> &mut [u8] {
+        self.buf.clear();
+        if self.buf.capacity() < size {
+            self.buf.reserve(PAYLOAD_POOL_SIZE.max(size));
+        }
+        self.buf.resize(size, 0);
+        &mut self.buf
+    }
+
+    /// Split off the payload prepared in `prepare`, or copy it if it is small.
+    fn take(&mut self) -> Bytes {
+        if self.buf.len() < PAYLOAD_COPY_THRESHOLD {
+            let payload = Bytes::copy_from_slice(&self.buf);
+            self.buf.clear();
+            payload
+        } else {
+            self.buf.split().freeze()
+        }
+    }
+}
+
+pub struct ShannonEncoder<T> {
+    inner: T,
+    cipher: ShannonCipher,
+    buf: Vec<u8>,
+}
+
+impl<T> ShannonEncoder<T>
+where
+    T: io::Write,
+{
+    pub fn new(inner: T, send_key: &[u8]) -> Self {
+        Self {
+            inner,
+            cipher: ShannonCipher::new(send_key),
+            buf: Vec::new(),
+        }
+    }
 
-        self.inner.write_all(&buf)
+    pub fn encode(&mut self, item: ShannonMsg) -> io::Result<()> {
+        self.encode_slice(item.cmd, &item.payload)
+    }
+
+    /// Encode a message with a borrowed payload.  Frames are buffered in a
+    /// buffer reused for the whole connection.
+    pub fn encode_slice(&mut self, cmd: u8, payload: &[u8]) -> io::Result<()> {
+        self.cipher.seal(cmd, payload, &mut self.buf);
+        self.inner.write_all(&self.buf)
     }
 
     pub fn as_inner_mut(&mut self) -> &mut T {
@@ -89,10 +188,13 @@ where
     }
 }
 
+/// Decodes the payloads into a `PayloadPool`, so most frames do not allocate.
+/// Holding on to a large payload is fine, but keeps the whole pool buffer
+/// alive.
 pub struct ShannonDecoder<T> {
     inner: T,
-    nonce: u32,
-    cipher: Shannon,
+    cipher: ShannonCipher,
+    pool: PayloadPool,
 }
 
 impl<T> ShannonDecoder<T>
@@ -102,30 +204,23 @@ where
     pub fn new(inner: T, recv_key: &[u8]) -> Self {
         Self {
             inner,
-            nonce: 0,
-            cipher: Shannon::new(recv_key),
+            cipher: ShannonCipher::new(recv_key),
+            pool: PayloadPool::new(),
         }
     }
 
     pub fn decode(&mut self) -> io::Result<ShannonMsg> {
-        // Seed the cipher and rotate the nonce.
-        self.cipher.nonce_u32(self.nonce);
-        self.nonce += 1;
-
         // Read the whole header.  Reading and decrypting byte by byte is not really
         // reliable, because of a bug in `shannon` crate.
         let mut header = [0_u8; HEADER_SIZE];
         self.inner.read_exact(&mut header)?;
-        self.cipher.decrypt(&mut header);
-
-        // Parse the header fields.
-        let cmd = header[0];
-        let size = u16::from_be_bytes([header[1], header[2]]) as usize;
+        let (cmd, size) = self.cipher.open_header(&mut header);
 
         // Read and decrypt the payload.
-        let mut payload = vec![0_u8; size];
-        self.inner.read_exact(&mut payload)?;
-        self.cipher.decrypt(&mut payload);
+        let payload = self.pool.prepare(size);
+        self.inner.read_exact(payload)?;
+        self.cipher.open_payload(payload);
+        let payload = self.pool.take();
 
         // Read and check the MAC.
         let mut mac = [0_u8; MAC_SIZE];
@@ -139,3 +234,82 @@ where
         &self.inner
     }
 }
+
+/// Like `ShannonEncoder`, but writing into an async stream.
+#[cfg(feature = "async")]
+pub struct AsyncShannonEncoder<T> {
+    inner: T,
+    cipher: ShannonCipher,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "async")]
+impl<T> AsyncShannonEncoder<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    pub fn new(inner: T, send_key: &[u8]) -> Self {
+        Self {
+            inner,
+            cipher: ShannonCipher::new(send_key),
+            buf: Vec::new(),
+        }
+    }
+
+    pub async fn encode(&mut self, item: ShannonMsg) -> io::Result<()> {
+        self.encode_slice(item.cmd, &item.payload).await
+    }
+
+    pub async fn encode_slice(&mut self, cmd: u8, payload: &[u8]) -> io::Result<()> {
+        self.cipher.seal(cmd, payload, &mut self.buf);
+        self.inner.write_all(&self.buf).await
+    }
+
+    pub fn as_inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// Like `ShannonDecoder`, but reading from an async stream.  Decoding is not
+/// cancel-safe, a frame read only partially breaks the stream.
+#[cfg(feature = "async")]
+pub struct AsyncShannonDecoder<T> {
+    inner: T,
+    cipher: ShannonCipher,
+    pool: PayloadPool,
+}
+
+#[cfg(feature = "async")]
+impl<T> AsyncShannonDecoder<T>
+where
+    T: AsyncRead + Unpin,
+{
+    pub fn new(inner: T, recv_key: &[u8]) -> Self {
+        Self {
+            inner,
+            cipher: ShannonCipher::new(recv_key),
+            pool: PayloadPool::new(),
+        }
+    }
+
+    pub async fn decode(&mut self) -> io::Result<ShannonMsg> {
+        let mut header = [0_u8; HEADER_SIZE];
+        self.inner.read_exact(&mut header).await?;
+        let (cmd, size) = self.cipher.open_header(&mut header);
+
+        let payload = self.pool.prepare(size);
+        self.inner.read_exact(payload).await?;
+        self.cipher.open_payload(payload);
+        let payload = self.pool.take();
+
+        let mut mac = [0_u8; MAC_SIZE];
+        self.inner.read_exact(&mut mac).await?;
+        self.cipher.check_mac(&mac)?;
+
+        Ok(ShannonMsg::new(cmd, payload))
+    }
+
+    pub fn as_inner(&self) -> &T {
+        &self.inner
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{bounded, unbounded, RecvTimeoutError};
+
+// Delay before racing the next address against the attempts already in flight,
+// as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first of `addrs` that accepts us before `deadline`.  Attempts
+/// are started one after another in `CONNECTION_ATTEMPT_DELAY` intervals,
+/// alternating between IPv6 and IPv4 addresses, and raced against each other
+/// ("Happy Eyeballs").  The returned error lists the failure of every address.
+pub fn connect_racing(addrs: Vec<SocketAddr>, deadline: Instant) -> io::Result<TcpStream> {
+    let addrs = interleave_families(addrs);
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        ));
+    }
+
+    let (result_send, result_recv) = unbounded();
+    let mut started = 0;
+    let mut failures = Vec::new();
+    let mut next_attempt = Instant::now();
+    loop {
+        let now = Instant::now();
+        if started < addrs.len() && now >= next_attempt {
+            let addr = addrs[started];
+            let result_send = result_send.clone();
+            thread::spawn(move || {
+                let result = match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => {
+                        TcpStream::connect_timeout(&addr, timeout)
+                    }
+                    _ => Err(io::ErrorKind::TimedOut.into()),
+                };
+                // If the race is over already, the stream is dropped and closed.
+                let _ = result_send.send((addr, result));
+            });
+            started += 1;
+            next_attempt = now + CONNECTION_ATTEMPT_DELAY;
+        }
+
+        let wait_until = if started < addrs.len() {
+            next_attempt.min(deadline)
+        } else {
+            deadline
+        };
+        match result_recv.recv_deadline(wait_until) {
+            Ok((addr, Ok(stream))) => {
+                log::trace!("connected to {}", addr);
+                return Ok(stream);
+            }
+            Ok((addr, Err(err))) => {
+                log::debug!("failed to connect to {}: {}", addr, err);
+                failures.push(format!("{}: {}", addr, err));
+                if failures.len() == addrs.len() {
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("failed to connect ({})", failures.join(", ")),
+                    ));
+                }
+                // Do not wait with the next attempt if this one failed.
+                next_attempt = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) if Instant::now() >= deadline => {
+                failures.push("timed out".into());
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("failed to connect ({})", failures.join(", ")),
+                ));
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Run the blocking address resolution `resolve` on a helper thread, and give
+/// up waiting for it at `deadline`.  The thread is left to finish in the
+/// background.
+pub fn resolve_before<F>(resolve: F, deadline: Instant) -> io::Result<Vec<SocketAddr>>
+where
+    F: FnOnce() -> io::Result<Vec<SocketAddr>> + Send + 'static,
+{
+    let (result_send, result_recv) = bounded(1);
+    thread::spawn(move || {
+        let _ = result_send.send(resolve());
+    });
+    match result_recv.recv_deadline(deadline) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out resolving the address",
+        )),
+        Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("address resolution failed")),
+    }
+}
+
+/// Order `addrs` so that IPv6 and IPv4 addresses alternate, starting with the
+/// family of the first address, keeping the relative order within a family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = !matches!(addrs.first(), Some(addr) if addr.is_ipv4());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut result = Vec::new();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+    result
+}
@@ -3,10 +3,17 @@ use std::{error, fmt, io};
 #[derive(Debug)]
 pub enum Error {
     SessionDisconnected,
+    RequestTimedOut,
+    MercuryNotFound,
+    MercuryForbidden,
+    MercuryRateLimited,
+    MercuryFailed { code: i32 },
+    ChannelFailed { code: u16 },
     UnexpectedResponse,
     MediaFileNotFound,
     ProxyUrlInvalid,
     AuthFailed { code: i32 },
+    OAuthError(String),
     JsonError(Box<dyn error::Error + Send>),
     AudioFetchingError(Box<dyn error::Error + Send>),
     AudioDecodingError(Box<dyn error::Error + Send>),
@@ -24,6 +31,16 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::SessionDisconnected => write!(f, "Session disconnected"),
+            Self::RequestTimedOut => write!(f, "Request timed out"),
+            Self::MercuryNotFound => write!(f, "Requested resource not found"),
+            Self::MercuryForbidden => write!(f, "Access to the requested resource denied"),
+            Self::MercuryRateLimited => write!(f, "Too many requests"),
+            Self::MercuryFailed { code } => {
+                write!(f, "Request failed with status code {}", code)
+            }
+            Self::ChannelFailed { code } => {
+                write!(f, "Channel failed with error code {}", code)
+            }
             Self::UnexpectedResponse => write!(f, "Unknown server response"),
             Self::MediaFileNotFound => write!(f, "Audio file not found"),
             Self::ProxyUrlInvalid => write!(f, "Invalid proxy URL"),
@@ -41,6 +58,7 @@ impl fmt::Display for Error {
                 17 => write!(f, "Authentication failed: application banned"),
                 _ => write!(f, "Authentication failed with error code {}", code),
             },
+            Self::OAuthError(err) => write!(f, "OAuth failed: {}", err),
             Self::ResamplingError(code) => {
                 write!(f, "Resampling failed with error code {}", code)
             }
@@ -1,114 +1,403 @@
 use std::{
-    io::{BufRead, BufReader, Write},
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
-    sync::mpsc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use oauth2::{
-    basic::BasicClient, reqwest::http_client, url::Url, AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl
+    basic::{BasicClient, BasicTokenType},
+    reqwest::http_client,
+    url::Url,
+    AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
 };
 
-use crate::session::access_token::CLIENT_ID;
+use crate::{
+    connection::Credentials,
+    error::Error,
+    session::{access_token::CLIENT_ID, SessionConfig, SessionConnection, DEFAULT_REQUEST_TIMEOUT},
+};
 
-pub fn get_authcode_listener(
-    socket_address: SocketAddr,
-    timeout: Duration,
-) -> Result<AuthorizationCode, String> {
-    log::info!("Starting OAuth listener on {:?}", socket_address);
-    let listener = TcpListener::bind(socket_address)
-        .map_err(|e| format!("Failed to bind to address: {}", e))?;
-    log::info!("Listener bound successfully");
+/// Port of the redirect URI registered for our client ID.
+pub const DEFAULT_REDIRECT_PORT: u16 = 8888;
 
-    let (tx, rx) = mpsc::channel();
+// Path of the redirect URI, the browser is sent there after the user logs in.
+const REDIRECT_PATH: &str = "/login";
 
-    let handle = std::thread::spawn(move || {
-        if let Ok((mut stream, _)) = listener.accept() {
-            handle_connection(&mut stream, &tx);
-        }
-    });
+// Interval of checking for cancellation while waiting for the redirect.
+const LISTENER_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-    let result = rx
-        .recv_timeout(timeout)
-        .map_err(|_| "Timed out waiting for authorization code".to_string())?;
+// Deadline of reading a single request from the browser.
+const LISTENER_READ_TIMEOUT: Duration = Duration::from_secs(5);
 
-    handle
-        .join()
-        .map_err(|_| "Failed to join server thread".to_string())?;
+// Limit of the request head lines we read, the rest is ignored.
+const LISTENER_MAX_HEAD_LINES: usize = 100;
 
-    result
+/// Local HTTP server the browser is redirected to with the authorization code
+/// after the user logs in.
+pub struct AuthCodeListener {
+    listener: TcpListener,
+    port: u16,
+    cancelled: Arc<AtomicBool>,
 }
 
-fn handle_connection(stream: &mut TcpStream, tx: &mpsc::Sender<Result<AuthorizationCode, String>>) {
-    let mut reader = BufReader::new(&mut *stream);
-    let mut request_line = String::new();
+/// Stops an `AuthCodeListener` waiting in another thread.
+#[derive(Clone)]
+pub struct AuthCodeListenerCancel {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl AuthCodeListenerCancel {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+impl AuthCodeListener {
+    /// Listen on `port` of the loopback interface.  The redirect URI with
+    /// `port` needs to be registered for our client ID, so port 0, picking any
+    /// free port, is rejected.
+    pub fn bind(port: u16) -> Result<Self, Error> {
+        check_redirect_port(port)?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(|err| {
+            Error::OAuthError(format!("failed to listen on port {}: {}", port, err))
+        })?;
+        // Accept in a non-blocking loop, so we can notice the cancellation.
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        log::info!("listening for OAuth redirect on port {}", port);
+        Ok(Self {
+            listener,
+            port,
+            cancelled: Arc::default(),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
 
-    if reader.read_line(&mut request_line).is_ok() {
-        if let Some(code) = extract_code_from_request(&request_line) {
-            send_success_response(stream);
-            let _ = tx.send(Ok(code));
-        } else {
-            let _ = tx.send(Err("Failed to extract code from request".to_string()));
+    pub fn cancel_handle(&self) -> AuthCodeListenerCancel {
+        AuthCodeListenerCancel {
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    /// Serve requests until the browser arrives at the redirect URI with the
+    /// `state` we sent in the authorization URL, or until cancelled or timed
+    /// out.  Other requests, like a favicon, are answered and ignored.
+    pub fn wait_for_code(
+        self,
+        state: &CsrfToken,
+        timeout: Duration,
+    ) -> Result<AuthorizationCode, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Err(Error::OAuthError("login cancelled".to_string()));
+            }
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Some(result) = handle_request(stream, state) {
+                        return result;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(Error::OAuthError(
+                            "timed out waiting for authorization code".to_string(),
+                        ));
+                    }
+                    thread::sleep(LISTENER_POLL_INTERVAL);
+                }
+                Err(err) => return Err(err.into()),
+            }
         }
     }
 }
 
-fn extract_code_from_request(request_line: &str) -> Option<AuthorizationCode> {
-    request_line.split_whitespace().nth(1).and_then(|path| {
-        Url::parse(&format!("http://localhost{}", path))
-            .ok()?
-            .query_pairs()
-            .find(|(key, _)| key == "code")
-            .map(|(_, code)| AuthorizationCode::new(code.into_owned()))
-    })
+/// Answer a single request.  Returns `None` if it was not the redirect we are
+/// waiting for.
+fn handle_request(
+    mut stream: TcpStream,
+    state: &CsrfToken,
+) -> Option<Result<AuthorizationCode, Error>> {
+    let url = match read_request_url(&mut stream) {
+        Ok(url) => url,
+        Err(err) => {
+            log::warn!("failed to read OAuth redirect request: {}", err);
+            return None;
+        }
+    };
+    if url.path() != REDIRECT_PATH {
+        send_response(&mut stream, "404 Not Found", "Not found.");
+        return None;
+    }
+    let params: HashMap<_, _> = url.query_pairs().collect();
+    if params.get("state").map(|value| value.as_ref()) != Some(state.secret().as_str()) {
+        // Not a response to our authorization request, possibly forged.
+        log::warn!("OAuth redirect with unexpected state");
+        send_response(
+            &mut stream,
+            "400 Bad Request",
+            "This login link has expired, please log in from Psst again.",
+        );
+        return None;
+    }
+    if let Some(error) = params.get("error") {
+        send_response(
+            &mut stream,
+            "403 Forbidden",
+            "Psst was not allowed to access your account.  You can close this window now.",
+        );
+        return Some(Err(authorization_error(error)));
+    }
+    match params.get("code") {
+        Some(code) => {
+            send_response(
+                &mut stream,
+                "200 OK",
+                "You are logged in.  You can close this window now.",
+            );
+            Some(Ok(AuthorizationCode::new(code.to_string())))
+        }
+        None => {
+            send_response(
+                &mut stream,
+                "400 Bad Request",
+                "Missing authorization code.",
+            );
+            Some(Err(Error::OAuthError(
+                "missing authorization code".to_string(),
+            )))
+        }
+    }
 }
 
-fn send_success_response(stream: &mut TcpStream) {
-    let response =
-        "HTTP/1.1 200 OK\r\n\r\n<html><body>You can close this window now.</body></html>";
+/// Read the request head, and return the URL of the request target.
+fn read_request_url(stream: &mut TcpStream) -> io::Result<Url> {
+    // Some platforms let the accepted socket inherit the non-blocking mode.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(LISTENER_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Consume the headers, closing the socket with unread data would reset the
+    // connection before the browser reads our response.
+    let mut line = String::new();
+    for _ in 0..LISTENER_MAX_HEAD_LINES {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed request"))?;
+    Url::parse("http://localhost")
+        .and_then(|base| base.join(target))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn send_response(stream: &mut TcpStream, status: &str, message: &str) {
+    let body = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Psst</title></head>\
+         <body><p>{}</p></body></html>",
+        message
+    );
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
     let _ = stream.write_all(response.as_bytes());
 }
 
-fn create_spotify_oauth_client(redirect_port: u16) -> BasicClient {
-    let redirect_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), redirect_port);
-    let redirect_uri = format!("http://{redirect_address}/login");
+/// Tokens received from the Spotify accounts service.
+#[derive(Clone, Debug)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: Option<Duration>,
+}
+
+impl OAuthToken {
+    fn from_response(response: &impl TokenResponse<BasicTokenType>) -> Self {
+        Self {
+            access_token: response.access_token().secret().to_string(),
+            refresh_token: response
+                .refresh_token()
+                .map(|token| token.secret().to_string()),
+            expires_in: response.expires_in(),
+        }
+    }
+}
 
-    BasicClient::new(
+fn authorization_error(error: &str) -> Error {
+    match error {
+        "access_denied" => Error::OAuthError("access denied".to_string()),
+        other => Error::OAuthError(format!("authorization failed with {}", other)),
+    }
+}
+
+fn create_spotify_oauth_client() -> Result<BasicClient, Error> {
+    Ok(BasicClient::new(
         ClientId::new(CLIENT_ID.to_string()),
         None,
-        AuthUrl::new("https://accounts.spotify.com/authorize".to_string()).unwrap(),
-        Some(TokenUrl::new("https://accounts.spotify.com/api/token".to_string()).unwrap()),
-    )
-    .set_redirect_uri(RedirectUrl::new(redirect_uri).expect("Invalid redirect URL"))
+        AuthUrl::new("https://accounts.spotify.com/authorize".to_string())
+            .map_err(|err| Error::OAuthError(err.to_string()))?,
+        Some(
+            TokenUrl::new("https://accounts.spotify.com/api/token".to_string())
+                .map_err(|err| Error::OAuthError(err.to_string()))?,
+        ),
+    ))
+}
+
+fn check_redirect_port(port: u16) -> Result<(), Error> {
+    if port == 0 {
+        return Err(Error::OAuthError(
+            "redirect port 0 is not registered for the client ID".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn redirect_url(redirect_port: u16) -> Result<RedirectUrl, Error> {
+    check_redirect_port(redirect_port)?;
+    let redirect_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), redirect_port);
+    RedirectUrl::new(format!("http://{redirect_address}{REDIRECT_PATH}"))
+        .map_err(|err| Error::OAuthError(err.to_string()))
 }
 
-pub fn generate_auth_url(redirect_port: u16) -> (String, PkceCodeVerifier) {
-    let client = create_spotify_oauth_client(redirect_port);
+/// Build the authorization URL to open in the browser.  Returns the PKCE
+/// verifier needed to exchange the code, and the `state` the listener expects.
+pub fn generate_auth_url(
+    redirect_port: u16,
+) -> Result<(String, PkceCodeVerifier, CsrfToken), Error> {
+    let client = create_spotify_oauth_client()?.set_redirect_uri(redirect_url(redirect_port)?);
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
-    let (auth_url, _) = client
+    let (auth_url, csrf_state) = client
         .authorize_url(CsrfToken::new_random)
         .add_scopes(get_scopes())
         .set_pkce_challenge(pkce_challenge)
         .url();
 
-    (auth_url.to_string(), pkce_verifier)
+    Ok((auth_url.to_string(), pkce_verifier, csrf_state))
 }
 
 pub fn exchange_code_for_token(
     redirect_port: u16,
     code: AuthorizationCode,
     pkce_verifier: PkceCodeVerifier,
-) -> String {
-    let client = create_spotify_oauth_client(redirect_port);
+) -> Result<OAuthToken, Error> {
+    let client = create_spotify_oauth_client()?.set_redirect_uri(redirect_url(redirect_port)?);
 
     let token_response = client
         .exchange_code(code)
         .set_pkce_verifier(pkce_verifier)
         .request(http_client)
-        .expect("Failed to exchange code for token");
+        .map_err(|err| Error::OAuthError(format!("failed to exchange code for token: {}", err)))?;
 
-    token_response.access_token().secret().to_string()
+    Ok(OAuthToken::from_response(&token_response))
+}
+
+/// Get a new access token without user interaction.  If the accounts service
+/// does not rotate the refresh token, the given one is kept.
+pub fn refresh_access_token(refresh_token: &str) -> Result<OAuthToken, Error> {
+    let client = create_spotify_oauth_client()?;
+
+    let token_response = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .request(http_client)
+        .map_err(|err| Error::OAuthError(format!("failed to refresh access token: {}", err)))?;
+
+    let mut token = OAuthToken::from_response(&token_response);
+    token
+        .refresh_token
+        .get_or_insert_with(|| refresh_token.to_string());
+    Ok(token)
+}
+
+/// Authorize on a machine without a browser.  The authorization URL is written
+/// to `output`, to be opened on any other device.  After logging in, the
+/// browser is redirected to a local address that fails to load, and the user
+/// pastes the URL from its address bar (or just its `code` parameter) into
+/// `input`.
+pub fn authorize_headless(
+    redirect_port: u16,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<OAuthToken, Error> {
+    let (auth_url, pkce_verifier, csrf_state) = generate_auth_url(redirect_port)?;
+    writeln!(
+        output,
+        "Open this URL in a browser, and log in:\n\n{}\n",
+        auth_url
+    )?;
+    writeln!(
+        output,
+        "The browser then fails to load a page on 127.0.0.1, paste its URL here:"
+    )?;
+    output.flush()?;
+
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(Error::OAuthError("no redirect URL entered".to_string()));
+    }
+    let code = parse_pasted_redirect(line.trim(), &csrf_state)?;
+    exchange_code_for_token(redirect_port, code, pkce_verifier)
+}
+
+/// Log in through `authorize_headless` on the standard input and output, and
+/// return the reusable credentials.  They can be serialized into a config
+/// file, and used to connect later without logging in again.
+pub fn login_headless(redirect_port: u16, proxy_url: Option<String>) -> Result<Credentials, Error> {
+    let token = authorize_headless(redirect_port, &mut io::stdin().lock(), &mut io::stdout())?;
+    let connection = SessionConnection::open(SessionConfig {
+        login_creds: Credentials::from_oauth_token(token),
+        proxy_url,
+        request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        pinned_ap: None,
+        last_ap_path: None,
+        audio_key_cache_dir: None,
+    })?;
+    Ok(connection.credentials)
+}
+
+/// Get the authorization code from the redirect URL, or take `pasted` as the
+/// code itself if it is not a URL.  Unlike the URL, a bare code carries no
+/// `state` to check, it is accepted because the user copies it from the
+/// browser they just logged in with.
+fn parse_pasted_redirect(pasted: &str, state: &CsrfToken) -> Result<AuthorizationCode, Error> {
+    let Ok(url) = Url::parse(pasted) else {
+        if pasted.is_empty() || pasted.contains(char::is_whitespace) {
+            return Err(Error::OAuthError("invalid authorization code".to_string()));
+        }
+        return Ok(AuthorizationCode::new(pasted.to_string()));
+    };
+    let params: HashMap<_, _> = url.query_pairs().collect();
+    if params.get("state").map(|value| value.as_ref()) != Some(state.secret().as_str()) {
+        return Err(Error::OAuthError(
+            "redirect URL does not belong to this login".to_string(),
+        ));
+    }
+    if let Some(error) = params.get("error") {
+        return Err(authorization_error(error));
+    }
+    params
+        .get("code")
+        .map(|code| AuthorizationCode::new(code.to_string()))
+        .ok_or_else(|| Error::OAuthError("missing authorization code".to_string()))
 }
 
 fn get_scopes() -> Vec<Scope> {
@@ -117,3 +406,112 @@ fn get_scopes() -> Vec<Scope> {
         .map(|s| Scope::new(s.trim().to_string()))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    /// Send a request for `target` to `handle_request`, and return its result
+    /// along with the response the browser gets.
+    fn request(
+        target: &str,
+        state: &CsrfToken,
+    ) -> (Option<Result<AuthorizationCode, Error>>, String) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        write!(client, "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", target).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let result = handle_request(stream, state);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        (result, response)
+    }
+
+    #[test]
+    fn rejects_redirect_port_zero() {
+        assert!(matches!(
+            AuthCodeListener::bind(0),
+            Err(Error::OAuthError(_))
+        ));
+        assert!(matches!(generate_auth_url(0), Err(Error::OAuthError(_))));
+    }
+
+    #[test]
+    fn accepts_only_redirects_with_our_state() {
+        let state = CsrfToken::new("expected".to_string());
+
+        let (result, response) = request("/login?code=abc&state=forged", &state);
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        let (result, response) = request("/login?code=abc", &state);
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        let (result, response) = request("/favicon.ico", &state);
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 404 "));
+
+        let (result, response) = request("/login?code=abc&state=expected", &state);
+        assert_eq!(result.unwrap().unwrap().secret(), "abc");
+        assert!(response.starts_with("HTTP/1.1 200 "));
+    }
+
+    #[test]
+    fn fails_if_the_user_cancels_the_login() {
+        let state = CsrfToken::new("expected".to_string());
+        let (result, response) = request("/login?error=access_denied&state=expected", &state);
+        assert!(matches!(result, Some(Err(Error::OAuthError(msg))) if msg == "access denied"));
+        assert!(response.starts_with("HTTP/1.1 403 "));
+
+        // A forged cancellation does not end the login.
+        let (result, _) = request("/login?error=access_denied&state=forged", &state);
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn parses_pasted_redirect_urls_and_codes() {
+        let state = CsrfToken::new("expected".to_string());
+
+        let code = parse_pasted_redirect(
+            "http://127.0.0.1:8888/login?code=abc&state=expected",
+            &state,
+        )
+        .unwrap();
+        assert_eq!(code.secret(), "abc");
+
+        let code = parse_pasted_redirect("abc", &state).unwrap();
+        assert_eq!(code.secret(), "abc");
+        assert!(parse_pasted_redirect("", &state).is_err());
+        assert!(parse_pasted_redirect("abc def", &state).is_err());
+    }
+
+    #[test]
+    fn rejects_pasted_redirects_of_other_logins() {
+        let state = CsrfToken::new("expected".to_string());
+        let result =
+            parse_pasted_redirect("http://127.0.0.1:8888/login?code=abc&state=forged", &state);
+        assert!(matches!(result, Err(Error::OAuthError(_))));
+        let result = parse_pasted_redirect("http://127.0.0.1:8888/login?code=abc", &state);
+        assert!(matches!(result, Err(Error::OAuthError(_))));
+        let result = parse_pasted_redirect(
+            "http://127.0.0.1:8888/login?error=access_denied&state=expected",
+            &state,
+        );
+        assert!(matches!(result, Err(Error::OAuthError(msg)) if msg == "access denied"));
+    }
+
+    #[test]
+    fn stops_waiting_once_cancelled() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let listener = AuthCodeListener {
+            port: listener.local_addr().unwrap().port(),
+            listener,
+            cancelled: Arc::default(),
+        };
+        listener.cancel_handle().cancel();
+        let result = listener.wait_for_code(&CsrfToken::new_random(), Duration::from_secs(5));
+        assert!(matches!(result, Err(Error::OAuthError(msg)) if msg == "login cancelled"));
+    }
+}
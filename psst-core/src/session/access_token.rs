@@ -1,9 +1,16 @@
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use parking_lot::Mutex;
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use once_cell::sync::OnceCell;
+use parking_lot::{Condvar, Mutex};
 use serde::Deserialize;
 
-use crate::error::Error;
+use crate::{error::Error, util::Backoff};
 
 use super::SessionService;
 
@@ -18,6 +25,55 @@ pub const ACCESS_SCOPES: &str = "streaming,user-read-email,user-read-private,pla
 // minutes.
 const EXPIRATION_TIME_THRESHOLD: Duration = Duration::from_secs(60 * 30);
 
+// Refresh tokens in the background this long before they would expire, so
+// callers never have to wait for a new one.
+const REFRESH_AHEAD_TIME: Duration = Duration::from_secs(60 * 5);
+
+// Interval of checking the cached tokens in the background.
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Retries of token requests failing with a transient error.
+const REQUEST_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const REQUEST_RETRY_MAX_DELAY: Duration = Duration::from_secs(4);
+const REQUEST_RETRY_MAX_ATTEMPTS: u32 = 3;
+
+/// Client ID and set of scopes an access token is minted for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TokenScope {
+    client_id: String,
+    scopes: Vec<String>,
+}
+
+impl TokenScope {
+    pub fn new(client_id: impl Into<String>, scopes: &[&str]) -> Self {
+        let mut scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+        // Keep the scopes in a canonical order, so the same set is cached only
+        // once.
+        scopes.sort();
+        scopes.dedup();
+        Self {
+            client_id: client_id.into(),
+            scopes,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+}
+
+impl Default for TokenScope {
+    /// Our client ID with all the scopes we could possibly require.
+    fn default() -> Self {
+        let scopes: Vec<&str> = ACCESS_SCOPES.split(',').collect();
+        Self::new(CLIENT_ID, &scopes)
+    }
+}
+
 #[derive(Clone)]
 pub struct AccessToken {
     pub token: String,
@@ -25,14 +81,7 @@ pub struct AccessToken {
 }
 
 impl AccessToken {
-    fn expired() -> Self {
-        Self {
-            token: String::new(),
-            expires: Instant::now(),
-        }
-    }
-
-    pub fn request(session: &SessionService) -> Result<Self, Error> {
+    pub fn request(session: &SessionService, scope: &TokenScope) -> Result<Self, Error> {
         #[derive(Deserialize)]
         struct MercuryAccessToken {
             #[serde(rename = "expiresIn")]
@@ -43,7 +92,8 @@ impl AccessToken {
 
         let token: MercuryAccessToken = session.connected()?.get_mercury_json(format!(
             "hm://keymaster/token/authenticated?client_id={}&scope={}",
-            CLIENT_ID, ACCESS_SCOPES
+            scope.client_id,
+            scope.scopes.join(",")
         ))?;
 
         Ok(Self {
@@ -52,28 +102,222 @@ impl AccessToken {
         })
     }
 
+    /// Time left until the official expiration.
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+
     fn is_expired(&self) -> bool {
-        self.expires.saturating_duration_since(Instant::now()) < EXPIRATION_TIME_THRESHOLD
+        self.remaining() < EXPIRATION_TIME_THRESHOLD
+    }
+
+    fn is_expiring(&self) -> bool {
+        self.remaining() < EXPIRATION_TIME_THRESHOLD + REFRESH_AHEAD_TIME
     }
 }
 
+/// Cache of access tokens, one for each requested `TokenScope`.  Tokens are
+/// refreshed on a background thread ahead of their expiration, requests for an
+/// expired token wait only for a token of the same scope.
+#[derive(Default)]
 pub struct TokenProvider {
-    token: Mutex<AccessToken>,
+    shared: Arc<Shared>,
+    // Dropping the sender stops the refreshing thread.
+    refresher: OnceCell<Sender<()>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    tokens: Mutex<HashMap<TokenScope, TokenSlot>>,
+    requested: Condvar,
+}
+
+#[derive(Default)]
+struct TokenSlot {
+    token: Option<AccessToken>,
+    requesting: bool,
 }
 
 impl TokenProvider {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a token for the default scope, see `get_for`.
+    pub fn get(&self, session: &SessionService) -> Result<AccessToken, Error> {
+        self.get_for(session, &TokenScope::default())
+    }
+
+    /// Return a cached token for `scope`, or request a new one if it expired.
+    /// The first call starts the background refreshing with `session`.
+    pub fn get_for(
+        &self,
+        session: &SessionService,
+        scope: &TokenScope,
+    ) -> Result<AccessToken, Error> {
+        self.start_refresher(session);
+
+        let mut tokens = self.shared.tokens.lock();
+        loop {
+            let slot = tokens.entry(scope.clone()).or_default();
+            if let Some(token) = slot.token.as_ref().filter(|token| !token.is_expired()) {
+                return Ok(token.clone());
+            }
+            if !slot.requesting {
+                // Request the token ourselves, other callers wait for us.
+                slot.requesting = true;
+                break;
+            }
+            self.shared.requested.wait(&mut tokens);
+        }
+        drop(tokens);
+
+        let mut request = PendingRequest::new(&self.shared, scope.clone());
+        log::info!("access token expired, requesting");
+        let result = request_with_retries(session, scope);
+        request.token = result.as_ref().ok().cloned();
+        result
+    }
+
+    /// Time left until the cached token for `scope` officially expires, for
+    /// diagnostics.  Returns `None` if no token was requested yet.
+    pub fn remaining_lifetime(&self, scope: &TokenScope) -> Option<Duration> {
+        self.shared
+            .tokens
+            .lock()
+            .get(scope)
+            .and_then(|slot| slot.token.as_ref())
+            .map(AccessToken::remaining)
+    }
+
+    fn start_refresher(&self, session: &SessionService) {
+        self.refresher.get_or_init(|| {
+            let (stop_send, stop_recv) = bounded::<()>(0);
+            let shared = self.shared.clone();
+            let session = session.clone();
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) =
+                    stop_recv.recv_timeout(REFRESH_CHECK_INTERVAL)
+                {
+                    shared.refresh_expiring(&session);
+                }
+            });
+            stop_send
+        });
+    }
+}
+
+impl Shared {
+    fn refresh_expiring(&self, session: &SessionService) {
+        // Do not reconnect a session that was shut down, the next `get` will.
+        if !session.is_connected() {
+            return;
+        }
+        let expiring: Vec<PendingRequest> = self
+            .tokens
+            .lock()
+            .iter_mut()
+            .filter(|(_, slot)| {
+                !slot.requesting && matches!(&slot.token, Some(token) if token.is_expiring())
+            })
+            .map(|(scope, slot)| {
+                slot.requesting = true;
+                PendingRequest::new(self, scope.clone())
+            })
+            .collect();
+        for mut request in expiring {
+            log::info!("access token expiring, refreshing");
+            request.token = request_with_retries(session, &request.scope)
+                .inspect_err(|err| log::warn!("failed to refresh access token: {}", err))
+                .ok();
+        }
+    }
+
+    fn finish_request(&self, scope: &TokenScope, token: Option<AccessToken>) {
+        {
+            let mut tokens = self.tokens.lock();
+            let slot = tokens.entry(scope.clone()).or_default();
+            slot.requesting = false;
+            if let Some(token) = token {
+                slot.token.replace(token);
+            }
+        }
+        self.requested.notify_all();
+    }
+}
+
+/// Token request in flight for `scope`.  Dropping it clears the `requesting`
+/// flag of the slot and wakes up the waiting callers, even if the request
+/// panicked.
+struct PendingRequest<'a> {
+    shared: &'a Shared,
+    scope: TokenScope,
+    token: Option<AccessToken>,
+}
+
+impl<'a> PendingRequest<'a> {
+    fn new(shared: &'a Shared, scope: TokenScope) -> Self {
         Self {
-            token: Mutex::new(AccessToken::expired()),
+            shared,
+            scope,
+            token: None,
         }
     }
+}
 
-    pub fn get(&self, session: &SessionService) -> Result<AccessToken, Error> {
-        let mut token = self.token.lock();
-        if token.is_expired() {
-            log::info!("access token expired, requesting");
-            *token = AccessToken::request(session)?;
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        self.shared.finish_request(&self.scope, self.token.take());
+    }
+}
+
+fn request_with_retries(
+    session: &SessionService,
+    scope: &TokenScope,
+) -> Result<AccessToken, Error> {
+    let mut backoff = Backoff::new(
+        REQUEST_RETRY_INITIAL_DELAY,
+        REQUEST_RETRY_MAX_DELAY,
+        REQUEST_RETRY_MAX_ATTEMPTS,
+    );
+    loop {
+        match AccessToken::request(session, scope) {
+            Ok(token) => return Ok(token),
+            Err(err) if is_transient(&err) => match backoff.next_delay() {
+                Some(delay) => {
+                    log::warn!(
+                        "access token request failed, retrying in {:?}: {}",
+                        delay,
+                        err
+                    );
+                    thread::sleep(delay);
+                }
+                None => return Err(err),
+            },
+            Err(err) => return Err(err),
         }
-        Ok(token.clone())
+    }
+}
+
+/// Returns true if retrying the request might succeed.  Not so on a
+/// disconnected session, its worker has shut down and fails all requests.
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::RequestTimedOut | Error::MercuryRateLimited | Error::IoError(_) => true,
+        Error::MercuryFailed { code } => *code >= 500,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn does_not_retry_on_a_disconnected_session() {
+        assert!(!is_transient(&Error::SessionDisconnected));
+        assert!(is_transient(&Error::RequestTimedOut));
+        assert!(is_transient(&Error::MercuryFailed { code: 503 }));
+        assert!(!is_transient(&Error::MercuryFailed { code: 404 }));
     }
 }
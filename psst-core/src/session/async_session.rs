@@ -0,0 +1,505 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use psst_protocol::authentication::APWelcome;
+use quick_protobuf::MessageRead;
+use serde::de::DeserializeOwned;
+use tokio::{
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::{self, JoinHandle},
+    time::timeout,
+};
+
+use crate::{
+    audio::decrypt::AudioKey,
+    cache::AudioKeyCache,
+    connection::{
+        async_transport::AsyncTransport,
+        shannon_codec::{AsyncShannonDecoder, AsyncShannonEncoder, ShannonMsg},
+        Credentials, Transport,
+    },
+    error::Error,
+    item_id::{FileId, ItemId},
+    util::{deserialize_protobuf, NET_IO_TIMEOUT},
+};
+
+use super::{
+    attributes::{SessionAttributes, SessionAttributesStore},
+    first_part,
+    mercury::{MercuryRequest, MercuryResponse},
+    multi_get_batch_results, open_audio_key_cache,
+    response::{async_response_channel, AsyncPendingResponse},
+    DispatchCmd, Dispatchers, Flow, Keepalive, SessionConfig, SessionRequest, MULTI_GET_BATCH_SIZE,
+    REQUEST_SWEEP_INTERVAL,
+};
+
+/// Like `SessionConnection`, but opened asynchronously.
+pub struct AsyncSessionConnection {
+    /// Credentials re-usable in the next authentication.
+    pub credentials: Credentials,
+    /// Account details received on login.
+    pub welcome: APWelcome,
+    /// I/O codec for the Shannon messages.
+    pub transport: AsyncTransport,
+}
+
+impl AsyncSessionConnection {
+    /// Connect to the Spotify servers and authenticate with credentials
+    /// provided in `config`.  Unless an access point is pinned in the config,
+    /// the resolved access points are tried one by one.  Unlike
+    /// `SessionConnection::open`, rejected OAuth credentials are not refreshed.
+    pub async fn open(config: &SessionConfig) -> Result<Self, Error> {
+        let ap_list = match &config.pinned_ap {
+            Some(ap) => vec![ap.clone()],
+            None => {
+                let proxy_url = config.proxy_url.clone();
+                task::spawn_blocking(move || {
+                    Transport::resolve_ap_list_with_fallback(proxy_url.as_deref())
+                })
+                .await
+                .map_err(io::Error::other)?
+            }
+        };
+        let mut last_err = None;
+        for ap in ap_list {
+            match Self::open_ap(&ap, config).await {
+                Ok(connection) => return Ok(connection),
+                Err(err @ Error::AuthFailed { code }) if code != 2 => return Err(err),
+                Err(err @ Error::ProxyUrlInvalid) => return Err(err),
+                Err(err) => {
+                    log::warn!("failed to connect to {}: {}", ap, err);
+                    last_err.replace(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or(Error::SessionDisconnected))
+    }
+
+    async fn open_ap(ap: &str, config: &SessionConfig) -> Result<Self, Error> {
+        let mut transport = AsyncTransport::connect(ap, config.proxy_url.as_deref()).await?;
+        let welcome = transport.authenticate(config.login_creds.clone()).await?;
+        Ok(Self {
+            credentials: Credentials {
+                refresh_token: config.login_creds.refresh_token.clone(),
+                ..Credentials::from_welcome(&welcome)
+            },
+            welcome,
+            transport,
+        })
+    }
+}
+
+/// Like `SessionWorker`, but servicing the connection with tasks on the
+/// current `tokio` runtime instead of threads.  It does not reconnect, once the
+/// connection breaks, all pending and future requests fail with
+/// `Error::SessionDisconnected`, and a new worker needs to be started.
+pub struct AsyncSessionWorker {
+    sender: UnboundedSender<DispatchCmd>,
+    request_timeout: Duration,
+    attributes: SessionAttributesStore,
+    audio_keys: Option<Arc<AudioKeyCache>>,
+    dispatching_task: JoinHandle<()>,
+}
+
+impl AsyncSessionWorker {
+    /// Open a connection with `config` and start servicing it.
+    pub async fn connect(config: &SessionConfig) -> Result<Self, Error> {
+        let connection = AsyncSessionConnection::open(config).await?;
+        Ok(Self::run(connection, config))
+    }
+
+    /// Start servicing an opened session connection.  Must be called from
+    /// within a `tokio` runtime.
+    pub fn run(connection: AsyncSessionConnection, config: &SessionConfig) -> Self {
+        let (disp_send, disp_recv) = unbounded_channel();
+        let attributes = SessionAttributesStore::new();
+        attributes.set_welcome(&connection.welcome);
+        let dispatcher = AsyncDispatcher {
+            dispatch: disp_recv,
+            dispatchers: Dispatchers::new(attributes.clone()),
+            keepalive: Keepalive::new(),
+            next_sweep: Instant::now() + REQUEST_SWEEP_INTERVAL,
+        };
+        Self {
+            dispatching_task: tokio::spawn(dispatcher.run(connection.transport, disp_send.clone())),
+            sender: disp_send,
+            request_timeout: config.request_timeout,
+            attributes,
+            audio_keys: open_audio_key_cache(config),
+        }
+    }
+
+    pub fn handle(&self) -> AsyncSessionHandle {
+        AsyncSessionHandle {
+            sender: self.sender.clone(),
+            request_timeout: self.request_timeout,
+            attributes: self.attributes.clone(),
+            audio_keys: self.audio_keys.clone(),
+        }
+    }
+
+    pub async fn join(self) {
+        if let Err(err) = self.dispatching_task.await {
+            log::error!("session dispatching task panicked: {:?}", err);
+        }
+    }
+
+    pub fn has_terminated(&self) -> bool {
+        self.dispatching_task.is_finished()
+    }
+}
+
+/// Async counterpart of `SessionHandle`.  Cheap to clone.
+#[derive(Clone)]
+pub struct AsyncSessionHandle {
+    sender: UnboundedSender<DispatchCmd>,
+    request_timeout: Duration,
+    attributes: SessionAttributesStore,
+    audio_keys: Option<Arc<AudioKeyCache>>,
+}
+
+impl AsyncSessionHandle {
+    pub async fn get_mercury_protobuf<T>(&self, uri: String) -> Result<T, Error>
+    where
+        T: MessageRead<'static>,
+    {
+        let payload = self.get_mercury_bytes(uri).await?;
+        let message = deserialize_protobuf(&payload)?;
+        Ok(message)
+    }
+
+    pub async fn get_mercury_json<T>(&self, uri: String) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let payload = self.get_mercury_bytes(uri).await?;
+        let message = serde_json::from_slice(&payload)?;
+        Ok(message)
+    }
+
+    /// Payload of a Mercury GET.  Shares the frame buffer, see
+    /// `MercuryResponse::payload`.
+    pub async fn get_mercury_bytes(&self, uri: String) -> Result<Bytes, Error> {
+        self.get_mercury_bytes_with_timeout(uri, self.request_timeout)
+            .await
+    }
+
+    pub async fn get_mercury_bytes_with_timeout(
+        &self,
+        uri: String,
+        timeout: Duration,
+    ) -> Result<Bytes, Error> {
+        let response = self
+            .mercury_request_with_timeout(MercuryRequest::get(uri), timeout)
+            .await?;
+        first_part(response)
+    }
+
+    /// See `SessionHandle::get_mercury_multi`.
+    pub async fn get_mercury_multi(
+        &self,
+        uris: &[String],
+    ) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        // Send all the batches up-front, so they are in flight concurrently.
+        let batches = uris
+            .chunks(MULTI_GET_BATCH_SIZE)
+            .map(|chunk| {
+                let request = MercuryRequest::multi_get(chunk);
+                Ok((
+                    chunk,
+                    self.send_mercury_request(request, self.request_timeout)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut results = Vec::with_capacity(uris.len());
+        for (chunk, pending) in batches {
+            match multi_get_batch_results(pending.wait().await, chunk.len())? {
+                Some(batch) => results.extend(batch),
+                None => results.extend(self.get_mercury_each(chunk).await?),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Like `get_mercury_multi`, but deserialize the payloads as protobuf
+    /// messages.
+    pub async fn get_mercury_protobuf_multi<T>(
+        &self,
+        uris: &[String],
+    ) -> Result<Vec<Result<T, Error>>, Error>
+    where
+        T: MessageRead<'static>,
+    {
+        let results = self
+            .get_mercury_multi(uris)
+            .await?
+            .into_iter()
+            .map(|payload| deserialize_protobuf(&payload?))
+            .collect();
+        Ok(results)
+    }
+
+    /// Fetch `uris` through individual, concurrently sent GET requests.
+    async fn get_mercury_each(
+        &self,
+        uris: &[String],
+    ) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        let pending = uris
+            .iter()
+            .map(|uri| {
+                let request = MercuryRequest::get(uri.clone());
+                self.send_mercury_request(request, self.request_timeout)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut results = Vec::with_capacity(pending.len());
+        for pending in pending {
+            let result = pending
+                .wait()
+                .await
+                .and_then(MercuryResponse::error_for_status)
+                .and_then(first_part)
+                .map(Vec::from);
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Send a Mercury request of any method and wait for the response.
+    /// Responses with a non-2xx status code are turned into errors.
+    pub async fn mercury_request(&self, request: MercuryRequest) -> Result<MercuryResponse, Error> {
+        self.mercury_request_with_timeout(request, self.request_timeout)
+            .await
+    }
+
+    pub async fn mercury_request_with_timeout(
+        &self,
+        request: MercuryRequest,
+        timeout: Duration,
+    ) -> Result<MercuryResponse, Error> {
+        self.send_mercury_request(request, timeout)?
+            .wait()
+            .await?
+            .error_for_status()
+    }
+
+    /// Send a Mercury request without waiting for the response.  Dropping the
+    /// returned `AsyncPendingResponse` cancels the request.
+    pub fn send_mercury_request(
+        &self,
+        request: MercuryRequest,
+        timeout: Duration,
+    ) -> Result<AsyncPendingResponse<MercuryResponse>, Error> {
+        let (callback, response) = async_response_channel(timeout);
+        self.send(SessionRequest::MercuryReq { request, callback })?;
+        Ok(response)
+    }
+
+    /// Like `SessionHandle::get_audio_key`.
+    pub async fn get_audio_key(&self, track: ItemId, file: FileId) -> Result<AudioKey, Error> {
+        self.get_audio_key_with_timeout(track, file, self.request_timeout)
+            .await
+    }
+
+    pub async fn get_audio_key_with_timeout(
+        &self,
+        track: ItemId,
+        file: FileId,
+        timeout: Duration,
+    ) -> Result<AudioKey, Error> {
+        let cache = self.audio_keys.as_deref();
+        if let Some(key) = cache.and_then(|cache| cache.get(track, file)) {
+            return Ok(key);
+        }
+        let (callback, response) = async_response_channel(timeout);
+        self.send(SessionRequest::AudioKeyReq {
+            track,
+            file,
+            callback,
+        })?;
+        let key = response.wait().await?;
+        if let Some(Err(err)) = cache.map(|cache| cache.save(track, file, key)) {
+            log::warn!("failed to save audio key to cache: {:?}", err);
+        }
+        Ok(key)
+    }
+
+    /// Like `SessionHandle::invalidate_audio_key`.
+    pub fn invalidate_audio_key(&self, track: ItemId, file: FileId) {
+        if let Some(cache) = &self.audio_keys {
+            if let Err(err) = cache.remove(track, file) {
+                log::warn!("failed to remove audio key from cache: {:?}", err);
+            }
+        }
+    }
+
+    /// Country code of the account, if the AP sent it already.
+    pub fn get_country_code(&self) -> Option<String> {
+        self.attributes.get().country_code
+    }
+
+    /// Wait until the AP sends the country code of the account, at most for
+    /// `timeout`.
+    pub async fn wait_for_country_code(&self, timeout: Duration) -> Option<String> {
+        self.attributes
+            .wait_until_async(|attrs| attrs.country_code.is_some(), timeout)
+            .await?
+            .country_code
+    }
+
+    /// Snapshot of the account attributes received so far.
+    pub fn attributes(&self) -> SessionAttributes {
+        self.attributes.get()
+    }
+
+    pub fn request_shutdown(&self) {
+        let _ = self.sender.send(DispatchCmd::Shutdown);
+    }
+
+    fn send(&self, request: SessionRequest) -> Result<(), Error> {
+        self.sender
+            .send(DispatchCmd::Request(request))
+            .ok()
+            .ok_or(Error::SessionDisconnected)
+    }
+}
+
+/// Async counterpart of `SessionDispatcher`, sharing its `Dispatchers`.
+struct AsyncDispatcher {
+    dispatch: UnboundedReceiver<DispatchCmd>,
+    dispatchers: Dispatchers,
+    keepalive: Keepalive,
+    next_sweep: Instant,
+}
+
+impl AsyncDispatcher {
+    async fn run(mut self, transport: AsyncTransport, dispatch_send: UnboundedSender<DispatchCmd>) {
+        let (msg_send, msg_recv) = unbounded_channel();
+        let decoding_task = tokio::spawn(decode_shannon_messages(
+            transport.decoder,
+            dispatch_send.clone(),
+        ));
+        let encoding_task = tokio::spawn(encode_shannon_messages(
+            transport.encoder,
+            msg_recv,
+            dispatch_send,
+        ));
+        loop {
+            let flow = match timeout(REQUEST_SWEEP_INTERVAL, self.dispatch.recv()).await {
+                Ok(Some(disp)) => self.handle(disp, &msg_send),
+                Ok(None) => Flow::Shutdown,
+                Err(_) => Flow::Continue,
+            };
+            let flow = match flow {
+                Flow::Continue if self.keepalive.is_overdue() => {
+                    log::error!(
+                        "connection dead, no ping received, last activity {:?} ago",
+                        self.keepalive.last_activity.elapsed()
+                    );
+                    Flow::Reconnect
+                }
+                flow => flow,
+            };
+            for msg in self.sweep_expired() {
+                let _ = msg_send.send(msg);
+            }
+            match flow {
+                Flow::Continue => {}
+                Flow::Reconnect | Flow::Shutdown => {
+                    // We do not reconnect, the owner starts a new worker instead.
+                    break;
+                }
+            }
+        }
+        // Dropping the message sender stops the encoding task, which closes the
+        // write half of the stream.
+        decoding_task.abort();
+        drop(msg_send);
+        if let Err(err) = encoding_task.await {
+            log::error!("session encoding task panicked: {:?}", err);
+        }
+        // Dropping the dispatchers drops the callbacks of all pending requests,
+        // and the waiting callers receive `Error::SessionDisconnected`.
+    }
+
+    fn sweep_expired(&mut self) -> Vec<ShannonMsg> {
+        let now = Instant::now();
+        if now < self.next_sweep {
+            return Vec::new();
+        }
+        self.next_sweep = now + REQUEST_SWEEP_INTERVAL;
+        self.dispatchers.sweep(now)
+    }
+
+    fn handle(&mut self, disp: DispatchCmd, messages: &UnboundedSender<ShannonMsg>) -> Flow {
+        match disp {
+            DispatchCmd::Request(request) => {
+                if let Some(msg) = self.dispatchers.enqueue(request) {
+                    let _ = messages.send(msg);
+                }
+            }
+            DispatchCmd::DecodedMsg(msg) => {
+                self.keepalive.record_activity();
+                if msg.cmd == ShannonMsg::PING {
+                    self.keepalive.record_ping();
+                }
+                if let Some(reply) = self.dispatchers.handle_msg(msg) {
+                    let _ = messages.send(reply);
+                }
+            }
+            DispatchCmd::DecoderError { error, .. } | DispatchCmd::EncoderError { error, .. } => {
+                log::error!("connection error: {:?}", error);
+                return Flow::Reconnect;
+            }
+            DispatchCmd::Shutdown => {
+                log::info!("connection shutdown");
+                return Flow::Shutdown;
+            }
+        }
+        Flow::Continue
+    }
+}
+
+/// Async counterpart of the decoding thread, see `decode_shannon_messages` of
+/// the blocking worker.  The connection has no epochs, as it is never replaced.
+async fn decode_shannon_messages(
+    mut decoder: AsyncShannonDecoder<OwnedReadHalf>,
+    dispatch: UnboundedSender<DispatchCmd>,
+) {
+    loop {
+        match decoder.decode().await {
+            Ok(msg) => {
+                if dispatch.send(DispatchCmd::DecodedMsg(msg)).is_err() {
+                    break;
+                }
+            }
+            Err(error) => {
+                let _ = dispatch.send(DispatchCmd::DecoderError { epoch: 0, error });
+                break;
+            }
+        }
+    }
+}
+
+/// Async counterpart of the encoding thread.  Writes are given
+/// `NET_IO_TIMEOUT`, like the write timeout of the blocking TCP stream.
+async fn encode_shannon_messages(
+    mut encoder: AsyncShannonEncoder<OwnedWriteHalf>,
+    mut messages: UnboundedReceiver<ShannonMsg>,
+    dispatch: UnboundedSender<DispatchCmd>,
+) {
+    while let Some(msg) = messages.recv().await {
+        let result = match timeout(NET_IO_TIMEOUT, encoder.encode(msg)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        };
+        if let Err(error) = result {
+            let _ = dispatch.send(DispatchCmd::EncoderError { epoch: 0, error });
+            break;
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use parking_lot::{Condvar, Mutex};
+use psst_protocol::authentication::{APWelcome, AccountType};
+
+/// Account and session information the AP sends right after login.
+#[derive(Clone, Debug, Default)]
+pub struct SessionAttributes {
+    pub canonical_username: Option<String>,
+    pub account_type: Option<AccountType>,
+    pub country_code: Option<String>,
+    /// Key-value pairs of the product info, i.e. `type`, `catalogue` or
+    /// `filter-explicit-content`.
+    pub product: HashMap<String, String>,
+}
+
+impl SessionAttributes {
+    /// Returns true if both the country code and the product info arrived.
+    pub fn is_complete(&self) -> bool {
+        self.country_code.is_some() && !self.product.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.product.get(key).map(String::as_str)
+    }
+
+    /// Product type of the account, i.e. `premium`, `free` or `open`.
+    pub fn product_type(&self) -> Option<&str> {
+        self.get("type")
+    }
+
+    pub fn is_premium(&self) -> Option<bool> {
+        self.product_type().map(|ty| ty == "premium")
+    }
+
+    pub fn catalogue(&self) -> Option<&str> {
+        self.get("catalogue")
+    }
+
+    pub fn filter_explicit_content(&self) -> bool {
+        self.get("filter-explicit-content") == Some("1")
+    }
+}
+
+/// Shared, observable store of the `SessionAttributes`.  Cheap to clone.
+#[derive(Clone, Default)]
+pub struct SessionAttributesStore {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    attributes: Mutex<SessionAttributes>,
+    changed: Condvar,
+    subscribers: Mutex<Vec<Sender<SessionAttributes>>>,
+    #[cfg(feature = "async")]
+    changed_async: tokio::sync::Notify,
+}
+
+impl SessionAttributesStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a snapshot of the current attributes.
+    pub fn get(&self) -> SessionAttributes {
+        self.shared.attributes.lock().clone()
+    }
+
+    /// Block until the attributes satisfy `predicate`, or `timeout` passes.
+    pub fn wait_until(
+        &self,
+        predicate: impl Fn(&SessionAttributes) -> bool,
+        timeout: Duration,
+    ) -> Option<SessionAttributes> {
+        let deadline = Instant::now() + timeout;
+        let mut attributes = self.shared.attributes.lock();
+        while !predicate(&attributes) {
+            if self
+                .shared
+                .changed
+                .wait_until(&mut attributes, deadline)
+                .timed_out()
+            {
+                return predicate(&attributes).then(|| attributes.clone());
+            }
+        }
+        Some(attributes.clone())
+    }
+
+    /// Like `wait_until`, but wait asynchronously.
+    #[cfg(feature = "async")]
+    pub async fn wait_until_async(
+        &self,
+        predicate: impl Fn(&SessionAttributes) -> bool,
+        timeout: Duration,
+    ) -> Option<SessionAttributes> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Register for the notification before checking, so a change in between
+            // is not missed.
+            let mut changed = std::pin::pin!(self.shared.changed_async.notified());
+            changed.as_mut().enable();
+            let attributes = self.get();
+            if predicate(&attributes) {
+                return Some(attributes);
+            }
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                return Some(self.get()).filter(predicate);
+            }
+        }
+    }
+
+    /// Receive a snapshot of the attributes after every change.
+    pub fn subscribe(&self) -> Receiver<SessionAttributes> {
+        let (sender, receiver) = unbounded();
+        self.shared.subscribers.lock().push(sender);
+        receiver
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut SessionAttributes)) {
+        let snapshot = {
+            let mut attributes = self.shared.attributes.lock();
+            f(&mut attributes);
+            attributes.clone()
+        };
+        self.shared.changed.notify_all();
+        #[cfg(feature = "async")]
+        self.shared.changed_async.notify_waiters();
+        self.shared
+            .subscribers
+            .lock()
+            .retain(|sender| sender.send(snapshot.clone()).is_ok());
+    }
+
+    pub fn set_welcome(&self, welcome: &APWelcome) {
+        self.update(|attributes| {
+            attributes
+                .canonical_username
+                .replace(welcome.canonical_username.clone());
+            attributes
+                .account_type
+                .replace(welcome.account_type_logged_in);
+        });
+    }
+}
+
+/// Parse the product info XML into its key-value pairs.  The document looks
+/// like `<products><product><type>premium</type>...</product></products>`, we
+/// only collect the leaf elements.
+pub fn parse_product_info(xml: &str) -> HashMap<String, String> {
+    let mut product = HashMap::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with(['/', '?', '!']) || tag.ends_with('/') {
+            continue;
+        }
+        let name = tag.split_whitespace().next().unwrap_or_default();
+        let closing = format!("</{}>", name);
+        match rest.find('<') {
+            Some(text_end) if rest[text_end..].starts_with(&closing) => {
+                product.insert(name.to_string(), unescape(&rest[..text_end]));
+                rest = &rest[text_end + closing.len()..];
+            }
+            _ => {
+                // Not a leaf element, descend into it.
+            }
+        }
+    }
+    product
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Product info of a premium account, as sent by the AP.
+    const PRODUCT_INFO: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<products>
+  <product>
+    <type>premium</type>
+    <catalogue>premium</catalogue>
+    <filter-explicit-content>1</filter-explicit-content>
+    <head-files-url>https://heads-fa.spotify.com/head/{file_id}</head-files-url>
+    <image-url>https://i.scdn.co/image/{file_id}</image-url>
+    <name>Premium Family &amp; Friends &lt;Duo&gt;</name>
+    <preferred-locale>en</preferred-locale>
+    <streaming-rules></streaming-rules>
+    <license-acceptance-grace/>
+    <offline><max-tracks>10000</max-tracks></offline>
+  </product>
+</products>"#;
+
+    fn parse(xml: &str) -> SessionAttributes {
+        SessionAttributes {
+            product: parse_product_info(xml),
+            ..SessionAttributes::default()
+        }
+    }
+
+    #[test]
+    fn parses_product_info() {
+        let attributes = parse(PRODUCT_INFO);
+        assert_eq!(attributes.product_type(), Some("premium"));
+        assert_eq!(attributes.is_premium(), Some(true));
+        assert_eq!(attributes.catalogue(), Some("premium"));
+        assert!(attributes.filter_explicit_content());
+        assert_eq!(
+            attributes.get("head-files-url"),
+            Some("https://heads-fa.spotify.com/head/{file_id}")
+        );
+        assert_eq!(attributes.get("streaming-rules"), Some(""));
+        // Only the leaves of nested elements are collected.
+        assert_eq!(attributes.get("max-tracks"), Some("10000"));
+        assert_eq!(attributes.get("offline"), None);
+        assert_eq!(attributes.get("product"), None);
+        assert_eq!(attributes.get("license-acceptance-grace"), None);
+    }
+
+    #[test]
+    fn unescapes_entities_once() {
+        let attributes = parse(PRODUCT_INFO);
+        assert_eq!(
+            attributes.get("name"),
+            Some("Premium Family & Friends <Duo>")
+        );
+        let attributes = parse("<products><product><name>a &amp;lt; b</name></product></products>");
+        assert_eq!(attributes.get("name"), Some("a &lt; b"));
+    }
+
+    #[test]
+    fn tolerates_missing_and_truncated_fields() {
+        let attributes = parse("<products><product><type>free</type></product></products>");
+        assert_eq!(attributes.is_premium(), Some(false));
+        assert_eq!(attributes.catalogue(), None);
+        assert!(!attributes.filter_explicit_content());
+
+        let attributes = parse("<products><product><type>premium");
+        assert_eq!(attributes.product_type(), None);
+        assert!(parse_product_info("").is_empty());
+    }
+}
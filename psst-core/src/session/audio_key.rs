@@ -1,10 +1,10 @@
 use std::{
     collections::HashMap,
     io::{Cursor, Read},
+    time::Instant,
 };
 
 use byteorder::{ReadBytesExt, BE};
-use crossbeam_channel::Sender;
 
 use crate::{
     audio::decrypt::AudioKey,
@@ -14,9 +14,17 @@ use crate::{
     util::Sequence,
 };
 
+use super::response::ResponseCallback;
+
 pub struct AudioKeyDispatcher {
     sequence: Sequence<u32>,
-    pending: HashMap<u32, Sender<Result<AudioKey, Error>>>,
+    pending: HashMap<u32, Pending>,
+}
+
+struct Pending {
+    track: ItemId,
+    file: FileId,
+    callback: ResponseCallback<AudioKey>,
 }
 
 impl AudioKeyDispatcher {
@@ -31,13 +39,48 @@ impl AudioKeyDispatcher {
         &mut self,
         track: ItemId,
         file: FileId,
-        callback: Sender<Result<AudioKey, Error>>,
+        callback: ResponseCallback<AudioKey>,
     ) -> ShannonMsg {
         let seq = self.sequence.advance();
-        self.pending.insert(seq, callback);
+        self.pending.insert(
+            seq,
+            Pending {
+                track,
+                file,
+                callback,
+            },
+        );
         Self::make_key_request(seq, track, file)
     }
 
+    /// Re-encode all key requests that have not been answered yet under fresh
+    /// sequence numbers.  Used after the session reconnects.
+    pub fn requeue_pending(&mut self) -> Vec<ShannonMsg> {
+        let mut pending: Vec<_> = self.pending.drain().collect();
+        pending.sort_by_key(|(seq, _)| *seq);
+        pending
+            .into_iter()
+            .map(|(_, pending)| self.enqueue_request(pending.track, pending.file, pending.callback))
+            .collect()
+    }
+
+    /// Fail the requests past their deadline with `Error::RequestTimedOut`, and
+    /// forget the requests nobody is waiting for anymore.
+    pub fn sweep(&mut self, now: Instant) {
+        self.pending.retain(|seq, pending| {
+            if pending.callback.is_cancelled() {
+                log::debug!("audio key request cancelled, seq: {}", seq);
+                false
+            } else if pending.callback.is_expired(now) {
+                log::warn!("audio key request timed out, seq: {}", seq);
+                let _ = pending.callback.send(Err(Error::RequestTimedOut));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
     fn make_key_request(seq: u32, track: ItemId, file: FileId) -> ShannonMsg {
         let mut buf = Vec::new();
         buf.extend(file.0);
@@ -49,13 +92,25 @@ impl AudioKeyDispatcher {
 
     pub fn handle_aes_key(&mut self, msg: ShannonMsg) {
         let mut payload = Cursor::new(msg.payload);
-        let seq = payload.read_u32::<BE>().unwrap();
+        let seq = match payload.read_u32::<BE>() {
+            Ok(seq) => seq,
+            Err(err) => {
+                log::error!("failed to read audio key seq: {}", err);
+                return;
+            }
+        };
 
-        if let Some(tx) = self.pending.remove(&seq) {
+        if let Some(pending) = self.pending.remove(&seq) {
             let mut key = [0_u8; 16];
-            payload.read_exact(&mut key).unwrap();
+            let result = match payload.read_exact(&mut key) {
+                Ok(_) => Ok(AudioKey(key)),
+                Err(_) => {
+                    log::error!("received truncated audio key, seq: {}", seq);
+                    Err(Error::UnexpectedResponse)
+                }
+            };
 
-            if tx.send(Ok(AudioKey(key))).is_err() {
+            if pending.callback.send(result).is_err() {
                 log::warn!("missing receiver for audio key, seq: {}", seq);
             }
         } else {
@@ -65,11 +120,21 @@ impl AudioKeyDispatcher {
 
     pub fn handle_aes_key_error(&mut self, msg: ShannonMsg) {
         let mut payload = Cursor::new(msg.payload);
-        let seq = payload.read_u32::<BE>().unwrap();
+        let seq = match payload.read_u32::<BE>() {
+            Ok(seq) => seq,
+            Err(err) => {
+                log::error!("failed to read audio key error seq: {}", err);
+                return;
+            }
+        };
 
-        if let Some(tx) = self.pending.remove(&seq) {
+        if let Some(pending) = self.pending.remove(&seq) {
             log::error!("audio key error");
-            if tx.send(Err(Error::UnexpectedResponse)).is_err() {
+            if pending
+                .callback
+                .send(Err(Error::UnexpectedResponse))
+                .is_err()
+            {
                 log::warn!("missing receiver for audio key error, seq: {}", seq);
             }
         } else {
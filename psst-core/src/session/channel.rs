@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    time::{Duration, Instant},
+};
+
+use byteorder::{ReadBytesExt, BE};
+use bytes::Bytes;
+use crossbeam_channel::{RecvTimeoutError, Sender};
+
+use crate::{
+    audio::decrypt::AudioDecrypt,
+    connection::shannon_codec::ShannonMsg,
+    error::Error,
+    item_id::{FileId, ItemId},
+    util::{read_bytes, Sequence},
+};
+
+use super::SessionHandle;
+
+// Size of the byte ranges `ChannelStream` requests at once.  Needs to be a
+// multiple of 4, channels address the file in 4-byte words.
+const CHUNK_SIZE: u64 = 128 * 1024;
+
+// Channel header carrying the total file size, in 4-byte words.
+const FILE_SIZE_HEADER_ID: u8 = 0x03;
+
+/// Part of a channel response, in order of arrival.  `End` is sent after the
+/// last `Data` event.
+#[derive(Debug)]
+pub enum ChannelEvent {
+    Header { id: u8, data: Bytes },
+    Data(Bytes),
+    End,
+}
+
+#[derive(Default)]
+pub struct ChannelDispatcher {
+    sequence: Sequence<u16>,
+    channels: HashMap<u16, Channel>,
+}
+
+struct Channel {
+    file: FileId,
+    // Requested byte range of the file.
+    start: u32,
+    end: u32,
+    // Bytes of the range delivered so far.
+    received: u32,
+    // Bytes to drop from the next data, if the range does not start on a word.
+    skip: u32,
+    state: ChannelState,
+    // Headers were delivered already, and are not repeated after a requeue.
+    headers_sent: bool,
+    // Deadline of the next response, pushed back by `timeout` every time one
+    // arrives.
+    timeout: Duration,
+    deadline: Instant,
+    events: Sender<Result<ChannelEvent, Error>>,
+}
+
+enum ChannelState {
+    Header,
+    Data,
+}
+
+/// Outcome of a `STREAM_CHUNK_RES` payload.
+enum Progress {
+    Continue,
+    Finished,
+    /// Nobody is reading the channel anymore.
+    Abandoned,
+}
+
+impl ChannelDispatcher {
+    pub fn new() -> Self {
+        Self {
+            sequence: Sequence::new(0),
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Allocate a channel for the `start..end` byte range of `file`, and return
+    /// the message requesting it.
+    pub fn enqueue_request(
+        &mut self,
+        file: FileId,
+        start: u32,
+        end: u32,
+        timeout: Duration,
+        events: Sender<Result<ChannelEvent, Error>>,
+    ) -> ShannonMsg {
+        let mut id = self.sequence.advance();
+        while self.channels.contains_key(&id) {
+            id = self.sequence.advance();
+        }
+        let channel = Channel {
+            file,
+            start,
+            end,
+            received: 0,
+            skip: start % 4,
+            state: ChannelState::Header,
+            headers_sent: false,
+            timeout,
+            deadline: Instant::now() + timeout,
+            events,
+        };
+        let msg = channel.make_request(id);
+        self.channels.insert(id, channel);
+        msg
+    }
+
+    /// Request the remaining part of all open channels again.  Used after the
+    /// session reconnects.
+    pub fn requeue_pending(&mut self) -> Vec<ShannonMsg> {
+        let mut ids: Vec<_> = self.channels.keys().copied().collect();
+        ids.sort_unstable();
+        ids.into_iter()
+            .map(|id| {
+                let channel = self.channels.get_mut(&id).unwrap();
+                channel.skip = (channel.start + channel.received) % 4;
+                channel.state = ChannelState::Header;
+                channel.deadline = Instant::now() + channel.timeout;
+                channel.make_request(id)
+            })
+            .collect()
+    }
+
+    /// Fail the channels that received nothing until their deadline with
+    /// `Error::RequestTimedOut`, and return the messages aborting them.
+    pub fn sweep(&mut self, now: Instant) -> Vec<ShannonMsg> {
+        let mut aborted = Vec::new();
+        self.channels.retain(|&id, channel| {
+            if now >= channel.deadline {
+                log::warn!("channel timed out, id: {}", id);
+                let _ = channel.events.send(Err(Error::RequestTimedOut));
+                aborted.push(abort_message(id));
+                false
+            } else {
+                true
+            }
+        });
+        aborted
+    }
+
+    /// Process a `STREAM_CHUNK_RES` message.  Returns the message aborting the
+    /// channel, if it is given up on.
+    pub fn handle_stream_chunk_res(&mut self, msg: ShannonMsg) -> Option<ShannonMsg> {
+        let mut payload = Cursor::new(msg.payload);
+        let id = match payload.read_u16::<BE>() {
+            Ok(id) => id,
+            Err(err) => {
+                log::error!("failed to read channel id: {}", err);
+                return None;
+            }
+        };
+        let Some(mut channel) = self.channels.remove(&id) else {
+            log::debug!("received data of unknown channel, id: {}", id);
+            return None;
+        };
+        match channel.handle_payload(payload) {
+            Ok(Progress::Continue) => {
+                self.channels.insert(id, channel);
+                None
+            }
+            Ok(Progress::Finished) => None,
+            Ok(Progress::Abandoned) => {
+                log::debug!("channel abandoned, id: {}", id);
+                Some(abort_message(id))
+            }
+            Err(err) => {
+                log::error!("failed to decode channel data, id: {}: {}", id, err);
+                let _ = channel.events.send(Err(err));
+                Some(abort_message(id))
+            }
+        }
+    }
+
+    pub fn handle_channel_error(&mut self, msg: ShannonMsg) {
+        let mut payload = Cursor::new(msg.payload);
+        let id = match payload.read_u16::<BE>() {
+            Ok(id) => id,
+            Err(err) => {
+                log::error!("failed to read channel id: {}", err);
+                return;
+            }
+        };
+        let code = payload.read_u16::<BE>().unwrap_or_default();
+        if let Some(channel) = self.channels.remove(&id) {
+            log::error!("channel error, id: {}, code: {}", id, code);
+            let _ = channel.events.send(Err(Error::ChannelFailed { code }));
+        } else {
+            log::warn!("received error of unknown channel, id: {}", id);
+        }
+    }
+
+    pub fn handle_channel_abort(&mut self, msg: ShannonMsg) {
+        let mut payload = Cursor::new(msg.payload);
+        let id = match payload.read_u16::<BE>() {
+            Ok(id) => id,
+            Err(err) => {
+                log::error!("failed to read channel id: {}", err);
+                return;
+            }
+        };
+        if let Some(channel) = self.channels.remove(&id) {
+            log::error!("channel aborted by the server, id: {}", id);
+            let _ = channel.events.send(Err(Error::UnexpectedResponse));
+        } else {
+            log::debug!("received abort of unknown channel, id: {}", id);
+        }
+    }
+}
+
+fn abort_message(id: u16) -> ShannonMsg {
+    ShannonMsg::new(ShannonMsg::CHANNEL_ABORT, id.to_be_bytes().to_vec())
+}
+
+impl Channel {
+    fn make_request(&self, id: u16) -> ShannonMsg {
+        let offset = self.start + self.received;
+        let mut buf = Vec::new();
+        buf.extend(id.to_be_bytes());
+        buf.extend([0x00, 0x01]);
+        buf.extend(0x0000_u16.to_be_bytes());
+        buf.extend(0x0000_0000_u32.to_be_bytes());
+        buf.extend(0x0000_9c40_u32.to_be_bytes());
+        buf.extend(0x0002_0000_u32.to_be_bytes());
+        buf.extend(self.file.0);
+        buf.extend((offset / 4).to_be_bytes());
+        buf.extend(self.end.div_ceil(4).to_be_bytes());
+        ShannonMsg::new(ShannonMsg::STREAM_CHUNK, buf)
+    }
+
+    /// Process one `STREAM_CHUNK_RES` payload.
+    fn handle_payload(&mut self, mut payload: Cursor<Bytes>) -> Result<Progress, Error> {
+        self.deadline = Instant::now() + self.timeout;
+        if let ChannelState::Header = self.state {
+            while (payload.position() as usize) < payload.get_ref().len() {
+                let len = payload.read_u16::<BE>()?;
+                if len == 0 {
+                    self.state = ChannelState::Data;
+                    self.headers_sent = true;
+                    break;
+                }
+                let id = payload.read_u8()?;
+                let data = read_bytes(&mut payload, usize::from(len) - 1)?;
+                if !self.headers_sent
+                    && self
+                        .events
+                        .send(Ok(ChannelEvent::Header { id, data }))
+                        .is_err()
+                {
+                    return Ok(Progress::Abandoned);
+                }
+            }
+            return Ok(Progress::Continue);
+        }
+
+        let mut data = payload.into_inner().slice(2..);
+        if data.is_empty() {
+            let _ = self.events.send(Ok(ChannelEvent::End));
+            return Ok(Progress::Finished);
+        }
+        let skip = (self.skip as usize).min(data.len());
+        data = data.slice(skip..);
+        self.skip -= skip as u32;
+        // Data arrives in whole words, cut off what is past the requested range.
+        let remaining = self.end.saturating_sub(self.start + self.received);
+        data.truncate(remaining as usize);
+        self.received += data.len() as u32;
+        if !data.is_empty() && self.events.send(Ok(ChannelEvent::Data(data))).is_err() {
+            return Ok(Progress::Abandoned);
+        }
+        Ok(Progress::Continue)
+    }
+}
+
+/// Audio file streamed through session channels, in chunks of `CHUNK_SIZE`
+/// bytes.  Still encrypted, wrap in `AudioDecrypt` for playback.
+pub struct ChannelStream {
+    session: SessionHandle,
+    file: FileId,
+    size: u64,
+    position: u64,
+    chunk_start: u64,
+    chunk: Vec<u8>,
+}
+
+impl ChannelStream {
+    /// Open the stream, fetching the first chunk to learn the file size.
+    pub fn open(session: SessionHandle, file: FileId) -> Result<Self, Error> {
+        let (size, chunk) = Self::fetch_chunk(&session, file, 0)?;
+        Ok(Self {
+            session,
+            file,
+            size: size.ok_or(Error::UnexpectedResponse)?,
+            position: 0,
+            chunk_start: 0,
+            chunk,
+        })
+    }
+
+    /// Open the stream of an Ogg Vorbis file, decrypted with the key of
+    /// `file`, see `SessionHandle::decrypt_audio`.
+    pub fn open_decrypted(
+        session: SessionHandle,
+        track: ItemId,
+        file: FileId,
+    ) -> Result<AudioDecrypt<Self>, Error> {
+        let stream = Self::open(session.clone(), file)?;
+        session.decrypt_audio(track, file, stream)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn fetch_chunk(
+        session: &SessionHandle,
+        file: FileId,
+        start: u64,
+    ) -> Result<(Option<u64>, Vec<u8>), Error> {
+        let start = u32::try_from(start).map_err(|_| Error::UnexpectedResponse)?;
+        let end = start.saturating_add(CHUNK_SIZE as u32);
+        let events = session.open_channel(file, start, end)?;
+        let mut size = None;
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+        loop {
+            match events.recv_timeout(session.request_timeout) {
+                Ok(Ok(ChannelEvent::Header { id, data })) if id == FILE_SIZE_HEADER_ID => {
+                    let words = Cursor::new(data).read_u32::<BE>()?;
+                    size.replace(u64::from(words) * 4);
+                }
+                Ok(Ok(ChannelEvent::Header { .. })) => {}
+                Ok(Ok(ChannelEvent::Data(data))) => {
+                    chunk.extend_from_slice(&data);
+                }
+                Ok(Ok(ChannelEvent::End)) => {
+                    break;
+                }
+                Ok(Err(err)) => {
+                    return Err(err);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::RequestTimedOut);
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::SessionDisconnected);
+                }
+            }
+        }
+        Ok((size, chunk))
+    }
+}
+
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk_end = self.chunk_start + self.chunk.len() as u64;
+        if !(self.chunk_start..chunk_end).contains(&self.position) {
+            let start = self.position - self.position % CHUNK_SIZE;
+            let (_, chunk) = Self::fetch_chunk(&self.session, self.file, start)
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            self.chunk_start = start;
+            self.chunk = chunk;
+        }
+        let offset = (self.position - self.chunk_start) as usize;
+        if offset >= self.chunk.len() {
+            // The chunk ends before `position`, the file is shorter than its
+            // reported size.
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let len = buf.len().min(self.chunk.len() - offset);
+        buf[..len].copy_from_slice(&self.chunk[offset..offset + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for ChannelStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+    use crate::session::{attributes::SessionAttributesStore, DispatchCmd, SessionRequest};
+
+    // Size the file claims to have, more than the server delivers.
+    const FILE_SIZE: u64 = 3 * CHUNK_SIZE;
+    const SHORT_CHUNK_SIZE: usize = 1000;
+
+    /// Session answering the channel requests with a full first chunk, and a
+    /// short one afterwards.
+    fn serve_short_file() -> SessionHandle {
+        let (sender, receiver) = unbounded();
+        thread::spawn(move || {
+            for cmd in receiver {
+                let DispatchCmd::Request(SessionRequest::ChannelReq { start, events, .. }) = cmd
+                else {
+                    continue;
+                };
+                let len = if start == 0 {
+                    CHUNK_SIZE as usize
+                } else {
+                    SHORT_CHUNK_SIZE
+                };
+                let words = (FILE_SIZE / 4) as u32;
+                let _ = events.send(Ok(ChannelEvent::Header {
+                    id: FILE_SIZE_HEADER_ID,
+                    data: Bytes::copy_from_slice(&words.to_be_bytes()),
+                }));
+                let _ = events.send(Ok(ChannelEvent::Data(vec![1; len].into())));
+                let _ = events.send(Ok(ChannelEvent::End));
+            }
+        });
+        SessionHandle {
+            sender,
+            request_timeout: Duration::from_secs(5),
+            attributes: SessionAttributesStore::new(),
+            audio_keys: None,
+        }
+    }
+
+    #[test]
+    fn reports_short_chunks_as_unexpected_eof() {
+        let mut stream = ChannelStream::open(serve_short_file(), FileId([0; 20])).unwrap();
+        assert_eq!(stream.size(), FILE_SIZE);
+
+        let mut buf = [0; 16];
+        stream.seek(SeekFrom::Start(CHUNK_SIZE + 10)).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), buf.len());
+
+        stream
+            .seek(SeekFrom::Start(CHUNK_SIZE + SHORT_CHUNK_SIZE as u64 + 10))
+            .unwrap();
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn aborts_abandoned_and_timed_out_channels() {
+        let mut dispatcher = ChannelDispatcher::new();
+        let timeout = Duration::from_secs(5);
+        let (events, receiver) = unbounded();
+        dispatcher.enqueue_request(FileId([0; 20]), 0, 1024, timeout, events);
+        let (events, _receiver) = unbounded();
+        dispatcher.enqueue_request(FileId([1; 20]), 0, 1024, timeout, events);
+        drop(receiver);
+
+        // Headers of the first channel, nobody reads them anymore.
+        let msg = ShannonMsg::new(ShannonMsg::STREAM_CHUNK_RES, vec![0, 0, 0, 3, 3, 0, 0]);
+        let abort = dispatcher.handle_stream_chunk_res(msg).unwrap();
+        assert_eq!(abort.cmd, ShannonMsg::CHANNEL_ABORT);
+        assert_eq!(&abort.payload[..], [0, 0]);
+
+        assert!(dispatcher.sweep(Instant::now()).is_empty());
+        let aborted = dispatcher.sweep(Instant::now() + timeout);
+        assert_eq!(aborted.len(), 1);
+        assert_eq!(&aborted[0].payload[..], [0, 1]);
+        assert!(dispatcher.requeue_pending().is_empty());
+    }
+}
@@ -1,21 +1,37 @@
 use std::{
     collections::HashMap,
-    io::{Cursor, Read},
+    io::Cursor,
+    iter,
+    time::{Duration, Instant},
 };
 
 use byteorder::{ReadBytesExt, BE};
+use bytes::{Bytes, BytesMut};
 use crossbeam_channel::Sender;
-use psst_protocol::mercury::Header;
+use psst_protocol::mercury::{Header, MercuryMultiGetReply, MercuryMultiGetRequest, UserField};
 
 use crate::{
     connection::shannon_codec::ShannonMsg,
     error::Error,
-    util::{deserialize_protobuf, serialize_protobuf, Sequence},
+    util::{deserialize_protobuf, read_bytes, serialize_protobuf, Sequence},
 };
 
+use super::response::ResponseCallback;
+
+// Content types marking the multi-get requests and replies, that pack many GET
+// requests into one round-trip.
+const MULTI_GET_REQUEST_CONTENT_TYPE: &str = "vnd.spotify/mercury-mget-request";
+const MULTI_GET_REPLY_CONTENT_TYPE: &str = "vnd.spotify/mercury-mget-reply";
+
+// Partially received events are dropped if their final part does not arrive
+// in this time.
+const PENDING_PUB_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct MercuryDispatcher {
     sequence: Sequence<u64>,
     pending: HashMap<u64, Pending>,
+    pending_pubs: HashMap<u64, PendingPub>,
+    subscriptions: Vec<Subscription>,
 }
 
 impl MercuryDispatcher {
@@ -23,27 +39,125 @@ impl MercuryDispatcher {
         Self {
             sequence: Sequence::new(0),
             pending: HashMap::new(),
+            pending_pubs: HashMap::new(),
+            subscriptions: Vec::new(),
         }
     }
 
     pub fn enqueue_request(
         &mut self,
         req: MercuryRequest,
-        callback: Sender<MercuryResponse>,
+        callback: ResponseCallback<MercuryResponse>,
     ) -> ShannonMsg {
         let seq = self.sequence.advance();
+        let msg = ShannonMsg::new(req.shannon_cmd(), req.encode_to_mercury_message(seq));
         self.pending.insert(
             seq,
             Pending {
+                request: req,
                 callback,
                 messages: Vec::new(),
             },
         );
-        ShannonMsg::new(ShannonMsg::MERCURY_REQ, req.encode_to_mercury_message(seq))
+        msg
+    }
+
+    /// Re-encode all GET and SUB requests that have not been answered yet
+    /// under fresh sequence numbers, dropping any partial responses received so
+    /// far.  Used after the session reconnects, because the server has no
+    /// notion of the requests sent over the previous connection.  The other
+    /// requests might have been applied already, so they fail with
+    /// `Error::SessionDisconnected` instead.
+    pub fn requeue_pending(&mut self) -> Vec<ShannonMsg> {
+        let mut pending: Vec<_> = self.pending.drain().collect();
+        pending.sort_by_key(|(seq, _)| *seq);
+        pending
+            .into_iter()
+            .filter_map(|(_, pending)| {
+                if pending.request.method.is_replayable() {
+                    Some(self.enqueue_request(pending.request, pending.callback))
+                } else {
+                    log::warn!(
+                        "mercury {} request interrupted: {}",
+                        pending.request.method.as_str(),
+                        pending.request.uri
+                    );
+                    let _ = pending.callback.send(Err(Error::SessionDisconnected));
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Register a subscription with ID `id` and return the SUB request for
+    /// sending.  Published messages with URI starting with the subscribed URI
+    /// are delivered to `events`, the SUB response itself goes to `callback`.
+    pub fn subscribe(
+        &mut self,
+        id: u64,
+        req: MercuryRequest,
+        events: Sender<MercuryResponse>,
+        callback: ResponseCallback<MercuryResponse>,
+    ) -> ShannonMsg {
+        self.subscriptions.push(Subscription {
+            id,
+            uri: req.uri.clone(),
+            events,
+        });
+        self.enqueue_request(req, callback)
+    }
+
+    /// Remove the subscription with ID `id`.  If it was the last subscription
+    /// of its URI, return the UNSUB request for sending.
+    pub fn unsubscribe(&mut self, id: u64) -> Option<ShannonMsg> {
+        let index = self.subscriptions.iter().position(|sub| sub.id == id)?;
+        let sub = self.subscriptions.remove(index);
+        if self.subscriptions.iter().any(|other| other.uri == sub.uri) {
+            None
+        } else {
+            // Nobody is waiting for the UNSUB response.
+            let callback = ResponseCallback::detached();
+            Some(self.enqueue_request(MercuryRequest::unsubscribe(sub.uri), callback))
+        }
+    }
+
+    /// Make SUB requests for all active subscriptions, except the ones that
+    /// are still waiting for their SUB response.  Used after the session
+    /// reconnects, because the server forgets the subscriptions together with
+    /// the connection.  Partially received events of the previous connection
+    /// are dropped, their remaining parts never arrive.
+    pub fn resubscribe(&mut self) -> Vec<ShannonMsg> {
+        self.pending_pubs.clear();
+        let mut uris: Vec<String> = self
+            .subscriptions
+            .iter()
+            .map(|sub| sub.uri.clone())
+            .filter(|uri| {
+                !self.pending.values().any(|pending| {
+                    pending.request.method == MercuryMethod::Sub && &pending.request.uri == uri
+                })
+            })
+            .collect();
+        uris.sort();
+        uris.dedup();
+        uris.into_iter()
+            .map(|uri| {
+                let callback = ResponseCallback::detached();
+                self.enqueue_request(MercuryRequest::subscribe(uri), callback)
+            })
+            .collect()
     }
 
     pub fn handle_mercury_req(&mut self, shannon_msg: ShannonMsg) {
-        let msg = Msg::decode(shannon_msg.payload);
+        let msg = match Msg::decode(shannon_msg.payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                // Without a valid sequence number, we cannot tell which request this
+                // message belongs to.
+                log::error!("failed to decode mercury msg: {}", err);
+                return;
+            }
+        };
         let msg_flags = msg.flags;
         let msg_seq = msg.seq;
         if let Some(mut pending) = self.pending.remove(&msg_seq) {
@@ -52,6 +166,13 @@ impl MercuryDispatcher {
                 // This is the final message.  Aggregate all pending parts and process further.
                 let parts = Msg::aggregate(pending.messages);
                 let response = MercuryResponse::decode_from_parts(parts);
+                if let Err(err) = &response {
+                    log::error!(
+                        "failed to decode mercury response, seq: {}: {}",
+                        msg_seq,
+                        err
+                    );
+                }
                 // Send the response.  If the response channel is closed, ignore it.
                 let _ = pending.callback.send(response);
             } else {
@@ -62,48 +183,206 @@ impl MercuryDispatcher {
             log::warn!("received unexpected mercury msg, seq: {}", msg_seq);
         }
     }
+
+    /// Fail the requests past their deadline with `Error::RequestTimedOut`, and
+    /// forget the requests nobody is waiting for anymore.  Partially received
+    /// events past their deadline are dropped.
+    pub fn sweep(&mut self, now: Instant) {
+        self.pending_pubs.retain(|seq, pending| {
+            if pending.deadline <= now {
+                log::warn!("mercury event timed out, seq: {}", seq);
+                false
+            } else {
+                true
+            }
+        });
+        self.pending.retain(|seq, pending| {
+            if pending.callback.is_cancelled() {
+                log::debug!("mercury request cancelled, seq: {}", seq);
+                false
+            } else if pending.callback.is_expired(now) {
+                log::warn!("mercury request timed out: {}", pending.request.uri);
+                let _ = pending.callback.send(Err(Error::RequestTimedOut));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub fn handle_mercury_pub(&mut self, shannon_msg: ShannonMsg) {
+        let msg = match Msg::decode(shannon_msg.payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                log::error!("failed to decode mercury event: {}", err);
+                return;
+            }
+        };
+        let msg_flags = msg.flags;
+        let msg_seq = msg.seq;
+        let mut pending = self
+            .pending_pubs
+            .remove(&msg_seq)
+            .unwrap_or_else(|| PendingPub {
+                messages: Vec::new(),
+                deadline: Instant::now() + PENDING_PUB_TIMEOUT,
+            });
+        pending.messages.push(msg);
+        if msg_flags == Msg::FINAL {
+            let parts = Msg::aggregate(pending.messages);
+            let event = match MercuryResponse::decode_from_parts(parts) {
+                Ok(event) => event,
+                Err(err) => {
+                    log::error!("failed to decode mercury event: {}", err);
+                    return;
+                }
+            };
+            let mut delivered = false;
+            // Deliver the event to all matching subscriptions.  Subscriptions with
+            // closed channels are dropped, their owners are gone.
+            self.subscriptions.retain(|sub| {
+                if event.uri.starts_with(&sub.uri) {
+                    delivered = true;
+                    sub.events.send(event.clone()).is_ok()
+                } else {
+                    true
+                }
+            });
+            if !delivered {
+                log::debug!("received mercury event without subscription: {}", event.uri);
+            }
+        } else {
+            self.pending_pubs.insert(msg_seq, pending);
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MercuryMethod {
+    Get,
+    Send,
+    Post,
+    Sub,
+    Unsub,
+}
+
+impl MercuryMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Send => "SEND",
+            Self::Post => "POST",
+            Self::Sub => "SUB",
+            Self::Unsub => "UNSUB",
+        }
+    }
+
+    /// Returns true if the request can be sent again without side effects, in
+    /// case it is not known whether the server received it.
+    pub fn is_replayable(&self) -> bool {
+        matches!(self, Self::Get | Self::Sub)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MercuryRequest {
     pub uri: String,
-    pub method: String,
+    pub method: MercuryMethod,
+    pub content_type: Option<String>,
+    pub user_fields: Vec<(String, Vec<u8>)>,
     pub payload: Vec<Vec<u8>>,
 }
 
 impl MercuryRequest {
-    pub fn get(uri: String) -> Self {
+    pub fn new(method: MercuryMethod, uri: String, payload: Vec<Vec<u8>>) -> Self {
         Self {
             uri,
-            method: "GET".to_string(),
-            payload: Vec::new(),
+            method,
+            content_type: None,
+            user_fields: Vec::new(),
+            payload,
         }
     }
 
+    pub fn get(uri: String) -> Self {
+        Self::new(MercuryMethod::Get, uri, Vec::new())
+    }
+
     pub fn send(uri: String, data: Vec<u8>) -> Self {
-        Self {
-            uri,
-            method: "SEND".to_string(),
-            payload: vec![data],
+        Self::new(MercuryMethod::Send, uri, vec![data])
+    }
+
+    pub fn post(uri: String, payload: Vec<Vec<u8>>) -> Self {
+        Self::new(MercuryMethod::Post, uri, payload)
+    }
+
+    pub fn subscribe(uri: String) -> Self {
+        Self::new(MercuryMethod::Sub, uri, Vec::new())
+    }
+
+    /// Pack GET requests of all `uris` into a single multi-get request.  Use
+    /// `MercuryResponse::into_multi_get_results` to split the response.
+    pub fn multi_get(uris: &[String]) -> Self {
+        let request = MercuryMultiGetRequest {
+            request: uris
+                .iter()
+                .map(|uri| psst_protocol::mercury::MercuryRequest {
+                    uri: Some(uri.clone()),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let payload = serialize_protobuf(&request).expect("Failed to serialize multi-get request");
+        Self::new(MercuryMethod::Get, multi_get_base_uri(uris), vec![payload])
+            .with_content_type(MULTI_GET_REQUEST_CONTENT_TYPE)
+    }
+
+    pub fn unsubscribe(uri: String) -> Self {
+        Self::new(MercuryMethod::Unsub, uri, Vec::new())
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_user_field(mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.user_fields.push((key.into(), value.into()));
+        self
+    }
+
+    fn shannon_cmd(&self) -> u8 {
+        match self.method {
+            MercuryMethod::Sub => ShannonMsg::MERCURY_SUB,
+            MercuryMethod::Unsub => ShannonMsg::MERCURY_UNSUB,
+            _ => ShannonMsg::MERCURY_REQ,
         }
     }
 
-    fn encode_to_mercury_message(self, seq: u64) -> Vec<u8> {
-        let parts = self.encode_to_parts();
-        let msg = Msg::new(seq, Msg::FINAL, parts);
-        msg.encode()
+    fn encode_to_mercury_message(&self, seq: u64) -> Vec<u8> {
+        let header_part = self.encode_header();
+        let parts: Vec<&[u8]> = iter::once(header_part.as_slice())
+            .chain(self.payload.iter().map(Vec::as_slice))
+            .collect();
+        Msg::encode(seq, Msg::FINAL, &parts)
     }
 
-    fn encode_to_parts(self) -> Vec<Vec<u8>> {
+    fn encode_header(&self) -> Vec<u8> {
         let header = Header {
-            uri: Some(self.uri),
-            method: Some(self.method),
+            uri: Some(self.uri.clone()),
+            method: Some(self.method.as_str().to_string()),
+            content_type: self.content_type.clone(),
+            user_fields: self
+                .user_fields
+                .iter()
+                .map(|(key, value)| UserField {
+                    key: Some(key.clone()),
+                    value: Some(value.clone()),
+                })
+                .collect(),
             ..Header::default()
         };
-        let header_part = serialize_protobuf(&header).expect("Failed to serialize message header");
-        let mut parts = self.payload;
-        parts.insert(0, header_part);
-        parts
+        serialize_protobuf(&header).expect("Failed to serialize message header")
     }
 }
 
@@ -111,95 +390,185 @@ impl MercuryRequest {
 pub struct MercuryResponse {
     pub uri: String,
     pub status_code: i32,
-    pub payload: Vec<Vec<u8>>,
+    pub content_type: Option<String>,
+    pub user_fields: Vec<(String, Vec<u8>)>,
+    /// Parts of the payload, usually sharing the buffers of the received
+    /// frames.  A part of a large frame keeps the whole 64 KiB frame buffer
+    /// alive, copy it if it is kept around for long.
+    pub payload: Vec<Bytes>,
 }
 
 impl MercuryResponse {
-    fn decode_from_parts(mut parts: Vec<Vec<u8>>) -> Self {
+    fn decode_from_parts(mut parts: Vec<Bytes>) -> Result<Self, Error> {
+        if parts.is_empty() {
+            return Err(Error::UnexpectedResponse);
+        }
         let header_part = parts.remove(0);
         let header: Header =
-            deserialize_protobuf(&header_part).expect("Failed to deserialize message header");
-        Self {
-            uri: header.uri.unwrap(),
-            status_code: header.status_code.unwrap(),
+            deserialize_protobuf(&header_part).map_err(|_| Error::ProtobufError)?;
+        Ok(Self {
+            uri: header.uri.ok_or(Error::UnexpectedResponse)?,
+            // Published events do not always carry a status code.
+            status_code: header.status_code.unwrap_or_default(),
+            content_type: header.content_type,
+            user_fields: header
+                .user_fields
+                .into_iter()
+                .filter_map(|field| Some((field.key?, field.value.unwrap_or_default())))
+                .collect(),
             payload: parts,
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status_code)
+    }
+
+    /// Turn a response with a non-2xx status code into an error.
+    pub fn error_for_status(self) -> Result<Self, Error> {
+        check_status_code(self.status_code)?;
+        Ok(self)
+    }
+
+    /// Split the response of a `MercuryRequest::multi_get` request into the
+    /// payloads of the individual requests, in the original order.
+    pub fn into_multi_get_results(self) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        match self.content_type.as_deref() {
+            Some(MULTI_GET_REPLY_CONTENT_TYPE) | None => {}
+            Some(_) => return Err(Error::UnexpectedResponse),
         }
+        let part = self.payload.first().ok_or(Error::UnexpectedResponse)?;
+        let reply: MercuryMultiGetReply =
+            deserialize_protobuf(part).map_err(|_| Error::ProtobufError)?;
+        let results = reply
+            .reply
+            .into_iter()
+            .map(|reply| {
+                check_status_code(reply.status_code.unwrap_or(200))?;
+                Ok(reply.body.unwrap_or_default())
+            })
+            .collect();
+        Ok(results)
+    }
+}
+
+fn check_status_code(status_code: i32) -> Result<(), Error> {
+    match status_code {
+        200..=299 => Ok(()),
+        401 | 403 => Err(Error::MercuryForbidden),
+        404 => Err(Error::MercuryNotFound),
+        429 => Err(Error::MercuryRateLimited),
+        code => Err(Error::MercuryFailed { code }),
+    }
+}
+
+/// Common prefix of `uris` up to the last path separator, used as the URI of
+/// the multi-get request itself.
+fn multi_get_base_uri(uris: &[String]) -> String {
+    let first = uris.first().map(String::as_str).unwrap_or_default();
+    let common_len = uris.iter().skip(1).fold(first.len(), |len, uri| {
+        first
+            .bytes()
+            .take(len)
+            .zip(uri.bytes())
+            .take_while(|(a, b)| a == b)
+            .count()
+    });
+    match first.as_bytes()[..common_len]
+        .iter()
+        .rposition(|&byte| byte == b'/')
+    {
+        Some(separator) => first[..=separator].to_string(),
+        None => first.to_string(),
     }
 }
 
+#[derive(Debug)]
+struct Subscription {
+    id: u64,
+    uri: String,
+    events: Sender<MercuryResponse>,
+}
+
 #[derive(Debug)]
 struct Pending {
+    request: MercuryRequest,
+    messages: Vec<Msg>,
+    callback: ResponseCallback<MercuryResponse>,
+}
+
+/// Parts of an event received so far.
+struct PendingPub {
     messages: Vec<Msg>,
-    callback: Sender<MercuryResponse>,
+    deadline: Instant,
 }
 
+/// Single Mercury message, carrying some or all parts of a request, response
+/// or event.
 #[derive(Debug, Default)]
-struct Msg {
-    seq: u64,
-    flags: u8,
-    count: u16,
-    parts: Vec<Vec<u8>>,
+pub(crate) struct Msg {
+    pub(crate) seq: u64,
+    pub(crate) flags: u8,
+    pub(crate) count: u16,
+    pub(crate) parts: Vec<Bytes>,
 }
 
 impl Msg {
-    const FINAL: u8 = 0x01;
-    const PARTIAL: u8 = 0x02;
-
-    fn new(seq: u64, flags: u8, parts: Vec<Vec<u8>>) -> Self {
-        let count = parts.len() as u16;
-        Self {
-            seq,
-            flags,
-            count,
-            parts,
-        }
-    }
+    pub(crate) const FINAL: u8 = 0x01;
+    pub(crate) const PARTIAL: u8 = 0x02;
 
-    fn decode(buf: Vec<u8>) -> Self {
+    /// Parse a Mercury message.  The parts are slices of `buf`, not copies.
+    pub(crate) fn decode(buf: Bytes) -> Result<Self, Error> {
         let mut buf = Cursor::new(buf);
-        let seq_len = buf.read_u16::<BE>().unwrap();
-        let seq = buf.read_uint::<BE>(seq_len.into()).unwrap();
-        let flags = buf.read_u8().unwrap();
-        let count = buf.read_u16::<BE>().unwrap();
+        let seq_len = buf.read_u16::<BE>()?;
+        if !(1..=8).contains(&seq_len) {
+            return Err(Error::UnexpectedResponse);
+        }
+        let seq = buf.read_uint::<BE>(seq_len.into())?;
+        let flags = buf.read_u8()?;
+        let count = buf.read_u16::<BE>()?;
         let mut parts = Vec::with_capacity(count.into());
         for _ in 0..count {
-            let part_len = buf.read_u16::<BE>().unwrap();
-            let mut part = vec![0_u8; part_len.into()];
-            buf.read_exact(&mut part).unwrap();
-            parts.push(part);
+            let part_len = buf.read_u16::<BE>()?;
+            parts.push(read_bytes(&mut buf, part_len.into())?);
         }
-        Self {
+        Ok(Self {
             seq,
             flags,
             count,
             parts,
-        }
+        })
     }
 
-    fn encode(&self) -> Vec<u8> {
+    pub(crate) fn encode(seq: u64, flags: u8, parts: &[&[u8]]) -> Vec<u8> {
+        let count = parts.len() as u16;
         let mut buf = Vec::new();
         buf.extend(8_u16.to_be_bytes()); // Sequence length.
-        buf.extend(self.seq.to_be_bytes());
-        buf.push(self.flags);
-        buf.extend(self.count.to_be_bytes());
-        for part in &self.parts {
+        buf.extend(seq.to_be_bytes());
+        buf.push(flags);
+        buf.extend(count.to_be_bytes());
+        for part in parts {
             let len = part.len() as u16;
             buf.extend(len.to_be_bytes());
-            buf.extend(part);
+            buf.extend(*part);
         }
         buf
     }
 
-    fn aggregate(msgs: impl IntoIterator<Item = Self>) -> Vec<Vec<u8>> {
+    /// Join the parts of a multi-message sequence.  Only the parts split across
+    /// messages are copied.
+    fn aggregate(msgs: impl IntoIterator<Item = Self>) -> Vec<Bytes> {
         let mut results = Vec::new();
-        let mut partial: Option<Vec<u8>> = None;
+        let mut partial: Option<Bytes> = None;
 
         for msg in msgs {
             for (i, mut part) in msg.parts.into_iter().enumerate() {
                 // If we have a partial data left from the last message, append to it.
-                if let Some(mut partial) = partial.take() {
-                    partial.extend(part);
-                    part = partial;
+                if let Some(partial) = partial.take() {
+                    let mut joined = BytesMut::with_capacity(partial.len() + part.len());
+                    joined.extend_from_slice(&partial);
+                    joined.extend_from_slice(&part);
+                    part = joined.freeze();
                 }
 
                 // Save the last part of partial messages for later.
@@ -221,3 +590,31 @@ impl From<quick_protobuf::Error> for Error {
         Error::IoError(err.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial_pub(seq: u64) -> ShannonMsg {
+        ShannonMsg::new(
+            ShannonMsg::MERCURY_PUB,
+            Msg::encode(seq, Msg::PARTIAL, &[b"header"]),
+        )
+    }
+
+    #[test]
+    fn drops_partial_events_on_reconnect_and_past_deadline() {
+        let mut dispatcher = MercuryDispatcher::new();
+        dispatcher.handle_mercury_pub(partial_pub(1));
+        dispatcher.handle_mercury_pub(partial_pub(2));
+        assert_eq!(dispatcher.pending_pubs.len(), 2);
+        dispatcher.resubscribe();
+        assert!(dispatcher.pending_pubs.is_empty());
+
+        dispatcher.handle_mercury_pub(partial_pub(3));
+        dispatcher.sweep(Instant::now());
+        assert_eq!(dispatcher.pending_pubs.len(), 1);
+        dispatcher.sweep(Instant::now() + PENDING_PUB_TIMEOUT);
+        assert!(dispatcher.pending_pubs.is_empty());
+    }
+}
@@ -1,40 +1,82 @@
 pub mod access_token;
+#[cfg(feature = "async")]
+pub mod async_session;
+pub mod attributes;
 pub mod audio_key;
+pub mod channel;
 pub mod mercury;
+pub mod response;
+pub mod state;
 
+use attributes::{parse_product_info, SessionAttributes, SessionAttributesStore};
 use audio_key::AudioKeyDispatcher;
-use parking_lot::Mutex;
+use bytes::Bytes;
+use channel::{ChannelDispatcher, ChannelEvent};
+use parking_lot::{Condvar, Mutex};
 use quick_protobuf::MessageRead;
 use serde::de::DeserializeOwned;
 use std::{
-    io,
+    fs,
+    io::{self, Read, Seek},
     net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
 use mercury::{MercuryDispatcher, MercuryRequest, MercuryResponse};
+use psst_protocol::authentication::APWelcome;
+use response::{response_channel, PendingResponse, ResponseCallback};
+use state::{ConnectionState, ConnectionStateStore};
 
 use crate::{
-    audio::decrypt::AudioKey,
+    audio::decrypt::{AudioDecrypt, AudioKey},
+    cache::{mkdir_if_not_exists, AudioKeyCache},
     connection::{
         shannon_codec::{ShannonDecoder, ShannonEncoder, ShannonMsg},
         Credentials, Transport,
     },
     error::Error,
     item_id::{FileId, ItemId},
-    util::deserialize_protobuf,
+    oauth,
+    util::{deserialize_protobuf, Backoff, NET_CONNECT_TIMEOUT},
 };
 
+// Default deadline for Mercury and audio key requests, if the caller does not
+// specify one.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Maximum number of URIs packed into a single multi-get request.
+const MULTI_GET_BATCH_SIZE: usize = 128;
+
+// How often the worker looks for pending requests past their deadline.
+const REQUEST_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// The AP pings us every two minutes.  If no ping arrives within this window,
+// the connection is considered dead.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
 /// Configuration values needed to open the session connection.
 #[derive(Clone)]
 pub struct SessionConfig {
     pub login_creds: Credentials,
     pub proxy_url: Option<String>,
+    /// Deadline of requests made through `SessionHandle` methods without an
+    /// explicit timeout.
+    pub request_timeout: Duration,
+    /// Access point to always connect to, instead of the resolved ones.
+    pub pinned_ap: Option<String>,
+    /// File remembering the last access point we successfully connected to.
+    /// It is tried first the next time.
+    pub last_ap_path: Option<PathBuf>,
+    /// Cache directory to keep the audio keys in, see `AudioKeyCache`.  If not
+    /// set, keys are requested from the AP every time.
+    pub audio_key_cache_dir: Option<PathBuf>,
 }
 
 /// Cheap to clone, shareable service handle that holds the active session
@@ -44,159 +86,560 @@ pub struct SessionConfig {
 /// request.
 #[derive(Clone)]
 pub struct SessionService {
-    connected: Arc<Mutex<Option<SessionWorker>>>,
-    config: Arc<Mutex<Option<SessionConfig>>>,
+    shared: Arc<ServiceShared>,
+    state: ConnectionStateStore,
+    refreshed_credentials: CredentialsSubscribers,
+}
+
+/// Receivers of the credentials renewed with the OAuth refresh token.
+type CredentialsSubscribers = Arc<Mutex<Vec<Sender<Credentials>>>>;
+
+fn publish_credentials(subscribers: &CredentialsSubscribers, credentials: &Credentials) {
+    subscribers
+        .lock()
+        .retain(|sender| sender.send(credentials.clone()).is_ok());
+}
+
+struct ServiceShared {
+    inner: Mutex<ServiceInner>,
+    changed: Condvar,
+    /// Credentials refreshed by the workers while reconnecting, to use in
+    /// place of the configured ones in the next connection attempt.
+    refreshed_credentials: Receiver<Credentials>,
+}
+
+#[derive(Default)]
+struct ServiceInner {
+    config: Option<SessionConfig>,
+    worker: Option<SessionWorker>,
+    /// Connection attempt in progress, shared by all callers of `connected`.
+    attempt: Option<ConnectAttempt>,
+    /// Error of the last failed attempt, for the callers that waited for it.
+    failure: Option<(u64, Error)>,
+    next_attempt_id: u64,
+}
+
+struct ConnectAttempt {
+    id: u64,
+    /// Cleared when the attempt is abandoned, stops it from publishing its
+    /// connection state.
+    active: Arc<AtomicBool>,
+}
+
+impl ServiceInner {
+    /// Abandon the connection attempt in progress, and return its ID.  The
+    /// attempt runs to completion in the background, but its result is
+    /// dropped.
+    fn cancel_attempt(&mut self) -> Option<u64> {
+        let attempt = self.attempt.take()?;
+        attempt.active.store(false, Ordering::SeqCst);
+        Some(attempt.id)
+    }
 }
 
 impl SessionService {
     /// Create a new session service without any configuration.  To open a
     /// session, a config needs to be set up first using `update_config`.
     pub fn empty() -> Self {
+        let (sender, receiver) = unbounded();
         Self {
-            connected: Arc::default(),
-            config: Arc::default(),
+            shared: Arc::new(ServiceShared {
+                inner: Mutex::default(),
+                changed: Condvar::new(),
+                refreshed_credentials: receiver,
+            }),
+            state: ConnectionStateStore::new(),
+            refreshed_credentials: Arc::new(Mutex::new(vec![sender])),
         }
     }
 
-    /// Replace the active session config.  If a session is already connected,
-    /// shut it down and wait until it's terminated.
+    /// Replace the active session config.  A connection attempt in progress is
+    /// abandoned, and its waiting callers connect again with the new config.
+    /// If a session is already connected, shut it down.  Does not wait for
+    /// the worker to terminate, it might be in the middle of reconnecting.
     pub fn update_config(&self, config: SessionConfig) {
-        self.config.lock().replace(config);
-        self.shutdown();
+        let worker = {
+            let mut inner = self.shared.inner.lock();
+            inner.config.replace(config);
+            // Credentials refreshed with the previous config do not apply anymore.
+            self.shared.refreshed_credentials.try_iter().for_each(drop);
+            if inner.cancel_attempt().is_some() {
+                self.state.set(ConnectionState::Disconnected);
+            }
+            inner.worker.take()
+        };
+        self.shared.changed.notify_all();
+        Self::shut_down_worker(worker);
     }
 
     /// Returns true if a session worker is actively servicing the connected
-    /// session.  We return false here after any case of I/O errors or an
-    /// explicit session shutdown.
+    /// session, and the connection is alive.  We return false here while
+    /// reconnecting after I/O errors or missed pings, and after an explicit
+    /// session shutdown.
     pub fn is_connected(&self) -> bool {
-        matches!(self.connected.lock().as_ref(), Some(worker) if !worker.has_terminated())
+        matches!(self.shared.inner.lock().worker.as_ref(), Some(worker) if worker.is_alive())
+    }
+
+    /// Current phase of the session connection.  Unlike `is_connected`, this
+    /// does not wait for a connection attempt in progress.
+    pub fn connection_state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// Receive the connection state every time it changes.
+    pub fn subscribe_connection_state(&self) -> Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Receive the credentials every time they are renewed with the OAuth
+    /// refresh token, so they can be saved in place of the rejected ones.
+    pub fn subscribe_refreshed_credentials(&self) -> Receiver<Credentials> {
+        let (sender, receiver) = unbounded();
+        self.refreshed_credentials.lock().push(sender);
+        receiver
     }
 
     /// Return a handle for the connected session.  In case no connection is
-    /// open, *synchronously* connect, start the worker and keep it as active.
-    /// Although a lock is held for the whole duration  of connection setup,
+    /// open, connect in the background, start the worker and keep it as
+    /// active, while we wait for it.  All concurrent callers wait for the same
+    /// connection attempt, and no lock is held while connecting.
     /// `SessionConnection::open` has an internal timeout, and should give up in
     /// a timely manner.
     pub fn connected(&self) -> Result<SessionHandle, Error> {
-        let mut connected = self.connected.lock();
-        let is_connected_and_not_terminated =
-            matches!(connected.as_ref(), Some(worker) if !worker.has_terminated());
-        if !is_connected_and_not_terminated {
-            let connection = SessionConnection::open(
-                self.config
-                    .lock()
-                    .as_ref()
-                    .ok_or(Error::SessionDisconnected)?
-                    .clone(),
-            )?;
-            let worker = SessionWorker::run(connection.transport);
-            connected.replace(worker);
-        }
-        connected
-            .as_ref()
-            .map(SessionWorker::handle)
-            .ok_or(Error::SessionDisconnected)
-    }
-
-    /// Signal a shutdown to the active worker and wait until it terminates.
+        self.wait_for_connection(None)
+    }
+
+    /// Like `connected`, but stop waiting after `timeout`.  The connection
+    /// attempt carries on for the other callers.
+    pub fn connected_timeout(&self, timeout: Duration) -> Result<SessionHandle, Error> {
+        self.wait_for_connection(Some(Instant::now() + timeout))
+    }
+
+    fn wait_for_connection(&self, deadline: Option<Instant>) -> Result<SessionHandle, Error> {
+        let mut inner = self.shared.inner.lock();
+        let mut waiting_for = None;
+        loop {
+            if let Some(worker) = inner.worker.as_ref() {
+                if !worker.has_terminated() {
+                    return Ok(worker.handle());
+                }
+            }
+            if let Some((id, err)) = &inner.failure {
+                if waiting_for == Some(*id) {
+                    return Err(copy_error(err));
+                }
+            }
+            let id = match &inner.attempt {
+                Some(attempt) => attempt.id,
+                None => self.start_attempt(&mut inner)?,
+            };
+            waiting_for.replace(id);
+            match deadline {
+                Some(deadline) => {
+                    if self
+                        .shared
+                        .changed
+                        .wait_until(&mut inner, deadline)
+                        .timed_out()
+                    {
+                        return Err(Error::RequestTimedOut);
+                    }
+                }
+                None => self.shared.changed.wait(&mut inner),
+            }
+        }
+    }
+
+    fn start_attempt(&self, inner: &mut ServiceInner) -> Result<u64, Error> {
+        let config = inner.config.as_mut().ok_or(Error::SessionDisconnected)?;
+        if let Some(credentials) = self.shared.refreshed_credentials.try_iter().last() {
+            config.login_creds = credentials;
+        }
+        let config = config.clone();
+        // Dispose of the terminated worker, if any.
+        inner.worker.take();
+        let id = inner.next_attempt_id;
+        inner.next_attempt_id += 1;
+        let active = Arc::new(AtomicBool::new(true));
+        inner.attempt.replace(ConnectAttempt {
+            id,
+            active: active.clone(),
+        });
+        let service = self.clone();
+        thread::spawn(move || service.run_attempt(id, config, active));
+        Ok(id)
+    }
+
+    fn run_attempt(&self, id: u64, config: SessionConfig, active: Arc<AtomicBool>) {
+        let result = SessionConnection::open_with_state(config.clone(), &self.state.gated(active));
+
+        let mut inner = self.shared.inner.lock();
+        if !matches!(&inner.attempt, Some(attempt) if attempt.id == id) {
+            // Abandoned in `update_config` or `shutdown`, drop the connection.
+            return;
+        }
+        inner.attempt = None;
+        match result {
+            Ok(connection) => {
+                if connection.refreshed {
+                    if let Some(config) = inner.config.as_mut() {
+                        config.login_creds = connection.credentials.clone();
+                    }
+                    publish_credentials(&self.refreshed_credentials, &connection.credentials);
+                }
+                let worker = SessionWorker::start(
+                    connection,
+                    config,
+                    self.state.clone(),
+                    self.refreshed_credentials.clone(),
+                );
+                inner.worker.replace(worker);
+            }
+            Err(err) => {
+                self.state.set(ConnectionState::Failed {
+                    error: err.to_string(),
+                });
+                inner.failure.replace((id, err));
+            }
+        }
+        drop(inner);
+        self.shared.changed.notify_all();
+    }
+
+    /// Signal a shutdown to the active worker, without waiting until it
+    /// terminates.  A connection attempt in progress is abandoned, and fails
+    /// for its waiting callers.
     pub fn shutdown(&self) {
-        if let Some(worker) = self.connected.lock().take() {
-            worker.handle().request_shutdown();
-            worker.join();
+        let worker = {
+            let mut inner = self.shared.inner.lock();
+            if let Some(id) = inner.cancel_attempt() {
+                inner.failure.replace((id, Error::SessionDisconnected));
+                self.state.set(ConnectionState::Disconnected);
+            }
+            inner.worker.take()
+        };
+        self.shared.changed.notify_all();
+        Self::shut_down_worker(worker);
+    }
+
+    fn shut_down_worker(worker: Option<SessionWorker>) {
+        if let Some(worker) = worker {
+            worker.request_shutdown();
+            thread::spawn(move || worker.join());
         }
     }
 }
 
+/// `Error` is not `Clone`, so every caller waiting for a failed connection
+/// attempt gets a copy, keeping the variants callers usually match on.
+fn copy_error(err: &Error) -> Error {
+    match err {
+        Error::SessionDisconnected => Error::SessionDisconnected,
+        Error::RequestTimedOut => Error::RequestTimedOut,
+        Error::ProxyUrlInvalid => Error::ProxyUrlInvalid,
+        Error::AuthFailed { code } => Error::AuthFailed { code: *code },
+        Error::OAuthError(message) => Error::OAuthError(message.clone()),
+        Error::IoError(err) => Error::IoError(io::Error::new(err.kind(), err.to_string())),
+        other => Error::IoError(io::Error::other(other.to_string())),
+    }
+}
+
 /// Successful connection through the Spotify Shannon-encrypted TCP channel.
 pub struct SessionConnection {
     /// Credentials re-usable in the next authentication (i.e. username and
     /// password are not required anymore).
     pub credentials: Credentials,
+    /// Account details received on login.
+    pub welcome: APWelcome,
     /// I/O codec for the Shannon messages.
     pub transport: Transport,
+    /// True if the original credentials were rejected, and we logged in with a
+    /// refreshed access token instead.  `credentials` should be saved again.
+    pub refreshed: bool,
 }
 
 impl SessionConnection {
     /// Synchronously connect to the Spotify servers and authenticate with
-    /// credentials provided in `config`.
+    /// credentials provided in `config`.  Unless an access point is pinned in
+    /// the config, the access points are tried one by one until one of them
+    /// accepts us.
     pub fn open(config: SessionConfig) -> Result<Self, Error> {
-        // Connect to the server and exchange keys.
+        Self::open_with_state(config, &ConnectionStateStore::new())
+    }
+
+    /// Like `open`, but report the progress into `state`.  If the AP rejects
+    /// the credentials and they include an OAuth refresh token, a new access
+    /// token is requested, and used to log in again.
+    pub fn open_with_state(
+        config: SessionConfig,
+        state: &ConnectionStateStore,
+    ) -> Result<Self, Error> {
+        match Self::open_any_ap(&config, state) {
+            Err(Error::AuthFailed { code }) if code != 2 => {
+                let Some(refresh_token) = &config.login_creds.refresh_token else {
+                    return Err(Error::AuthFailed { code });
+                };
+                log::info!("credentials rejected, refreshing access token");
+                let token = oauth::refresh_access_token(refresh_token)?;
+                let config = SessionConfig {
+                    login_creds: Credentials::from_oauth_token(token),
+                    ..config
+                };
+                let mut connection = Self::open_any_ap(&config, state)?;
+                connection.refreshed = true;
+                Ok(connection)
+            }
+            result => result,
+        }
+    }
+
+    fn open_any_ap(config: &SessionConfig, state: &ConnectionStateStore) -> Result<Self, Error> {
+        // All the APs tried share the connect timeout, so an unreachable proxy
+        // does not cost it once per AP.
+        let deadline = Instant::now() + NET_CONNECT_TIMEOUT;
+        let proxy_url = config.proxy_url.as_deref();
+        if let Some(ap) = &config.pinned_ap {
+            return Self::open_ap(ap, proxy_url, deadline, config.login_creds.clone(), state);
+        }
+
+        // Try the last working AP first, and resolve the AP list only if it fails.
+        let last_ap = config.last_ap_path.as_deref().and_then(load_last_ap);
+        let mut last_err = None;
+        if let Some(ap) = &last_ap {
+            if let Some(connection) =
+                Self::open_or_skip(ap, config, deadline, state, &mut last_err)?
+            {
+                return Ok(connection);
+            }
+        }
+        for ap in Transport::resolve_ap_list_with_fallback(proxy_url) {
+            if last_ap.as_ref() == Some(&ap) {
+                continue;
+            }
+            if let Some(connection) =
+                Self::open_or_skip(&ap, config, deadline, state, &mut last_err)?
+            {
+                return Ok(connection);
+            }
+        }
+        Err(last_err.unwrap_or(Error::SessionDisconnected))
+    }
+
+    /// Connect to `ap`.  Returns `None` if the next AP is worth trying, and an
+    /// error if the others would not accept us either.
+    fn open_or_skip(
+        ap: &str,
+        config: &SessionConfig,
+        deadline: Instant,
+        state: &ConnectionStateStore,
+        last_err: &mut Option<Error>,
+    ) -> Result<Option<Self>, Error> {
         let proxy_url = config.proxy_url.as_deref();
-        let ap_url = Transport::resolve_ap_with_fallback(proxy_url);
-        let mut transport = Transport::connect(&ap_url, proxy_url)?;
+        match Self::open_ap(ap, proxy_url, deadline, config.login_creds.clone(), state) {
+            Ok(connection) => {
+                if let Some(path) = &config.last_ap_path {
+                    save_last_ap(path, ap);
+                }
+                Ok(Some(connection))
+            }
+            Err(err @ Error::AuthFailed { code }) if code != 2 => Err(err),
+            Err(err @ Error::ProxyUrlInvalid) => Err(err),
+            Err(err) => {
+                log::warn!("failed to connect to {}: {}", ap, err);
+                last_err.replace(err);
+                Ok(None)
+            }
+        }
+    }
+
+    fn open_ap(
+        ap: &str,
+        proxy_url: Option<&str>,
+        deadline: Instant,
+        login_creds: Credentials,
+        state: &ConnectionStateStore,
+    ) -> Result<Self, Error> {
+        // Connect to the server and exchange keys.
+        state.set(ConnectionState::Connecting);
+        let mut transport = Transport::connect_before(ap, proxy_url, deadline)?;
         // Authenticate with provided credentials (either username/password, or saved,
         // reusable credential blob from an earlier run).
-        let credentials = transport.authenticate(config.login_creds)?;
+        state.set(ConnectionState::Authenticating);
+        let refresh_token = login_creds.refresh_token.clone();
+        let welcome = transport.authenticate(login_creds)?;
+        state.set(ConnectionState::Connected {
+            ap: ap.to_string(),
+            username: welcome.canonical_username.clone(),
+        });
         Ok(Self {
-            credentials,
+            credentials: Credentials {
+                refresh_token,
+                ..Credentials::from_welcome(&welcome)
+            },
+            welcome,
             transport,
+            refreshed: false,
         })
     }
 }
 
+fn load_last_ap(path: &Path) -> Option<String> {
+    let ap = fs::read_to_string(path).ok()?;
+    let ap = ap.trim();
+    (!ap.is_empty()).then(|| ap.to_string())
+}
+
+fn save_last_ap(path: &Path, ap: &str) {
+    let result = match path.parent() {
+        Some(dir) => mkdir_if_not_exists(dir).and_then(|_| fs::write(path, ap)),
+        None => fs::write(path, ap),
+    };
+    if let Err(err) = result {
+        log::warn!("failed to save last working AP: {:?}", err);
+    }
+}
+
+fn open_audio_key_cache(config: &SessionConfig) -> Option<Arc<AudioKeyCache>> {
+    let dir = config.audio_key_cache_dir.as_deref()?;
+    AudioKeyCache::new(dir)
+        .inspect_err(|err| log::warn!("failed to open audio key cache: {:?}", err))
+        .ok()
+        .map(Arc::new)
+}
+
 pub struct SessionWorker {
     sender: Sender<DispatchCmd>,
-    decoding_thread: JoinHandle<()>,
-    encoding_thread: JoinHandle<()>,
+    request_timeout: Duration,
+    attributes: SessionAttributesStore,
+    audio_keys: Option<Arc<AudioKeyCache>>,
+    refreshed_credentials: CredentialsSubscribers,
     dispatching_thread: JoinHandle<()>,
     terminated: Arc<AtomicBool>,
+    flags: WorkerFlags,
+    state: ConnectionStateStore,
+}
+
+/// Flags of a `SessionWorker`, shared with its dispatching thread.
+#[derive(Clone)]
+struct WorkerFlags {
+    /// Cleared while the connection is being re-established.
+    alive: Arc<AtomicBool>,
+    /// Cleared by `SessionWorker::request_shutdown`, stops the worker from
+    /// reconnecting and from publishing its connection state.
+    running: Arc<AtomicBool>,
 }
 
 impl SessionWorker {
-    pub fn run(transport: Transport) -> Self {
+    /// Start servicing an opened session connection.  In case the connection
+    /// breaks, the worker reconnects with `config` (using the reusable
+    /// credentials from `connection` instead of the original ones) and
+    /// re-issues all requests that were not answered yet.
+    pub fn run(
+        connection: SessionConnection,
+        config: SessionConfig,
+        state: ConnectionStateStore,
+    ) -> Self {
+        Self::start(connection, config, state, Arc::default())
+    }
+
+    fn start(
+        connection: SessionConnection,
+        config: SessionConfig,
+        state: ConnectionStateStore,
+        refreshed_credentials: CredentialsSubscribers,
+    ) -> Self {
         let (disp_send, disp_recv) = unbounded();
-        let (msg_send, msg_recv) = unbounded();
         let terminated = Arc::new(AtomicBool::new(false));
+        let flags = WorkerFlags {
+            alive: Arc::new(AtomicBool::new(true)),
+            running: Arc::new(AtomicBool::new(true)),
+        };
+        let attributes = SessionAttributesStore::new();
+        attributes.set_welcome(&connection.welcome);
+        let audio_keys = open_audio_key_cache(&config);
+        let config = SessionConfig {
+            login_creds: connection.credentials,
+            ..config
+        };
         Self {
-            decoding_thread: {
-                let decoder = transport.decoder;
-                let disp_send = disp_send.clone();
-                thread::spawn(move || decode_shannon_messages(decoder, disp_send))
-            },
-            encoding_thread: {
-                let encoder = transport.encoder;
-                let disp_send = disp_send.clone();
-                thread::spawn(move || encode_shannon_messages(encoder, msg_recv, disp_send))
-            },
+            request_timeout: config.request_timeout,
+            audio_keys,
             dispatching_thread: {
-                let stream = transport.stream;
+                let dispatcher = SessionDispatcher::new(
+                    disp_recv,
+                    disp_send.clone(),
+                    config,
+                    attributes.clone(),
+                    refreshed_credentials.clone(),
+                    flags.clone(),
+                    state.gated(flags.running.clone()),
+                );
+                let transport = connection.transport;
                 let terminated = terminated.clone();
                 thread::spawn(move || {
-                    dispatch_messages(disp_recv, msg_send, stream);
+                    dispatcher.run(transport);
                     terminated.store(true, Ordering::SeqCst);
                 })
             },
             sender: disp_send,
+            attributes,
+            refreshed_credentials,
             terminated,
+            flags,
+            state,
         }
     }
 
+    /// Receive the credentials every time the worker reconnects with a
+    /// refreshed access token, so they can be saved in place of the rejected
+    /// ones.
+    pub fn subscribe_refreshed_credentials(&self) -> Receiver<Credentials> {
+        let (sender, receiver) = unbounded();
+        self.refreshed_credentials.lock().push(sender);
+        receiver
+    }
+
     pub fn handle(&self) -> SessionHandle {
         SessionHandle {
             sender: self.sender.clone(),
+            request_timeout: self.request_timeout,
+            attributes: self.attributes.clone(),
+            audio_keys: self.audio_keys.clone(),
         }
     }
 
+    /// Signal a shutdown, unlike `SessionHandle::request_shutdown` also to a
+    /// reconnect attempt in progress.  The connection state is reported as
+    /// disconnected right away, the worker does not publish it anymore.
+    pub fn request_shutdown(&self) {
+        self.flags.running.store(false, Ordering::SeqCst);
+        self.handle().request_shutdown();
+        self.state.set(ConnectionState::Disconnected);
+    }
+
     pub fn join(self) {
         if let Err(err) = self.dispatching_thread.join() {
             log::error!("session dispatching thread panicked: {:?}", err);
         }
-        if let Err(err) = self.encoding_thread.join() {
-            log::error!("session encoding thread panicked: {:?}", err);
-        }
-        if let Err(err) = self.decoding_thread.join() {
-            log::error!("session decoding thread panicked: {:?}", err);
-        }
     }
 
     pub fn has_terminated(&self) -> bool {
         self.terminated.load(Ordering::SeqCst)
     }
+
+    /// Returns true if the worker is running and its connection is alive, i.e.
+    /// not being re-established.
+    pub fn is_alive(&self) -> bool {
+        !self.has_terminated() && self.flags.alive.load(Ordering::SeqCst)
+    }
 }
 
 #[derive(Clone)]
 pub struct SessionHandle {
     sender: Sender<DispatchCmd>,
+    request_timeout: Duration,
+    attributes: SessionAttributesStore,
+    audio_keys: Option<Arc<AudioKeyCache>>,
 }
 
 impl SessionHandle {
@@ -218,41 +661,256 @@ impl SessionHandle {
         Ok(message)
     }
 
-    pub fn get_mercury_bytes(&self, uri: String) -> Result<Vec<u8>, Error> {
-        let (callback, receiver) = unbounded();
-        let request = MercuryRequest::get(uri);
+    /// Payload of a Mercury GET.  Shares the frame buffer, see
+    /// `MercuryResponse::payload`.
+    pub fn get_mercury_bytes(&self, uri: String) -> Result<Bytes, Error> {
+        self.get_mercury_bytes_with_timeout(uri, self.request_timeout)
+    }
+
+    pub fn get_mercury_bytes_with_timeout(
+        &self,
+        uri: String,
+        timeout: Duration,
+    ) -> Result<Bytes, Error> {
+        let response = self.mercury_request_with_timeout(MercuryRequest::get(uri), timeout)?;
+        first_part(response)
+    }
+
+    /// Fetch the payloads of many Mercury URIs, packed into multi-get
+    /// requests.  Results are returned in the order of `uris`.  Batches that
+    /// the server refuses are fetched again as individual requests.
+    pub fn get_mercury_multi(&self, uris: &[String]) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        // Send all the batches up-front, so they are in flight concurrently.
+        let batches = uris
+            .chunks(MULTI_GET_BATCH_SIZE)
+            .map(|chunk| {
+                let request = MercuryRequest::multi_get(chunk);
+                Ok((
+                    chunk,
+                    self.send_mercury_request(request, self.request_timeout)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut results = Vec::with_capacity(uris.len());
+        for (chunk, pending) in batches {
+            match multi_get_batch_results(pending.wait(), chunk.len())? {
+                Some(batch) => results.extend(batch),
+                None => results.extend(self.get_mercury_each(chunk)?),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Like `get_mercury_multi`, but deserialize the payloads as protobuf
+    /// messages.
+    pub fn get_mercury_protobuf_multi<T>(
+        &self,
+        uris: &[String],
+    ) -> Result<Vec<Result<T, Error>>, Error>
+    where
+        T: MessageRead<'static>,
+    {
+        let results = self
+            .get_mercury_multi(uris)?
+            .into_iter()
+            .map(|payload| deserialize_protobuf(&payload?))
+            .collect();
+        Ok(results)
+    }
+
+    /// Fetch `uris` through individual, concurrently sent GET requests.
+    fn get_mercury_each(&self, uris: &[String]) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        let pending = uris
+            .iter()
+            .map(|uri| {
+                let request = MercuryRequest::get(uri.clone());
+                self.send_mercury_request(request, self.request_timeout)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let results = pending
+            .into_iter()
+            .map(|pending| first_part(pending.wait()?.error_for_status()?).map(Vec::from))
+            .collect();
+        Ok(results)
+    }
+
+    /// Send a Mercury request of any method and wait for the response.
+    /// Responses with a non-2xx status code are turned into errors.
+    pub fn mercury_request(&self, request: MercuryRequest) -> Result<MercuryResponse, Error> {
+        self.mercury_request_with_timeout(request, self.request_timeout)
+    }
+
+    pub fn mercury_request_with_timeout(
+        &self,
+        request: MercuryRequest,
+        timeout: Duration,
+    ) -> Result<MercuryResponse, Error> {
+        self.send_mercury_request(request, timeout)?
+            .wait()?
+            .error_for_status()
+    }
+
+    /// Send a Mercury request without waiting for the response.  Dropping the
+    /// returned `PendingResponse` cancels the request.
+    pub fn send_mercury_request(
+        &self,
+        request: MercuryRequest,
+        timeout: Duration,
+    ) -> Result<PendingResponse<MercuryResponse>, Error> {
+        let (callback, response) = response_channel(timeout);
         self.sender
-            .send(DispatchCmd::MercuryReq { callback, request })
+            .send(DispatchCmd::Request(SessionRequest::MercuryReq {
+                callback,
+                request,
+            }))
             .ok()
             .ok_or(Error::SessionDisconnected)?;
-        let response = receiver.recv().ok().ok_or(Error::SessionDisconnected)?;
-        let first_part = response
-            .payload
-            .into_iter()
-            .next()
-            .ok_or(Error::UnexpectedResponse)?;
-        Ok(first_part)
+        Ok(response)
+    }
+
+    /// Subscribe to Mercury events published under `uri`.  Blocks until the
+    /// server confirms the subscription.
+    pub fn subscribe_mercury(&self, uri: String) -> Result<MercurySubscription, Error> {
+        let (callback, response) = response_channel(self.request_timeout);
+        let (events_send, events) = unbounded();
+        let id = MercurySubscription::fresh_id();
+        self.sender
+            .send(DispatchCmd::Request(SessionRequest::MercurySub {
+                id,
+                request: MercuryRequest::subscribe(uri.clone()),
+                events: events_send,
+                callback,
+            }))
+            .ok()
+            .ok_or(Error::SessionDisconnected)?;
+        // Construct the subscription right away, so it unsubscribes on drop even if
+        // the server refuses the request.
+        let subscription = MercurySubscription {
+            id,
+            uri,
+            events,
+            sender: self.sender.clone(),
+        };
+        response.wait()?.error_for_status()?;
+        Ok(subscription)
     }
 
+    /// Key of the audio `file`, from the audio key cache if configured, or
+    /// requested from the AP.  Prefer `decrypt_audio`, which also replaces
+    /// cached keys that do not decrypt the file.
     pub fn get_audio_key(&self, track: ItemId, file: FileId) -> Result<AudioKey, Error> {
-        let (callback, receiver) = unbounded();
+        self.get_audio_key_with_timeout(track, file, self.request_timeout)
+    }
+
+    pub fn get_audio_key_with_timeout(
+        &self,
+        track: ItemId,
+        file: FileId,
+        timeout: Duration,
+    ) -> Result<AudioKey, Error> {
+        let request = || self.request_audio_key(track, file, timeout);
+        match &self.audio_keys {
+            Some(cache) => cache.get_or_request(track, file, request),
+            None => request(),
+        }
+    }
+
+    /// Decrypt the Ogg file `encrypted` with the key of `file`.  Keys from the
+    /// audio key cache are checked, see `AudioKeyCache::decrypt`.
+    pub fn decrypt_audio<R: Read + Seek>(
+        &self,
+        track: ItemId,
+        file: FileId,
+        encrypted: R,
+    ) -> Result<AudioDecrypt<R>, Error> {
+        let request = || self.request_audio_key(track, file, self.request_timeout);
+        match &self.audio_keys {
+            Some(cache) => cache.decrypt(track, file, encrypted, request),
+            None => Ok(AudioDecrypt::new(request()?, encrypted)),
+        }
+    }
+
+    /// Forget the cached key of `file`, e.g. after the audio it decrypted
+    /// failed to decode.  The next lookup requests it from the AP again.
+    pub fn invalidate_audio_key(&self, track: ItemId, file: FileId) {
+        if let Some(cache) = &self.audio_keys {
+            if let Err(err) = cache.remove(track, file) {
+                log::warn!("failed to remove audio key from cache: {:?}", err);
+            }
+        }
+    }
+
+    /// Request the key of `file` from the AP, bypassing the cache.
+    fn request_audio_key(
+        &self,
+        track: ItemId,
+        file: FileId,
+        timeout: Duration,
+    ) -> Result<AudioKey, Error> {
+        let (callback, response) = response_channel(timeout);
         self.sender
-            .send(DispatchCmd::AudioKeyReq {
+            .send(DispatchCmd::Request(SessionRequest::AudioKeyReq {
                 callback,
                 track,
                 file,
-            })
+            }))
             .ok()
             .ok_or(Error::SessionDisconnected)?;
-        receiver.recv().ok().ok_or(Error::SessionDisconnected)?
+        response.wait()
     }
 
-    pub fn get_country_code(&self) -> Option<String> {
-        let (callback, receiver) = unbounded();
+    /// Request the `start..end` byte range of `file` over a session channel.
+    /// The returned receiver yields the response parts as they arrive.  The
+    /// channel fails if nothing arrives within the request timeout, and is
+    /// aborted once the receiver is dropped.
+    pub fn open_channel(
+        &self,
+        file: FileId,
+        start: u32,
+        end: u32,
+    ) -> Result<Receiver<Result<ChannelEvent, Error>>, Error> {
+        let (events, receiver) = unbounded();
         self.sender
-            .send(DispatchCmd::CountryCodeReq { callback })
-            .ok()?;
-        receiver.recv().ok()?
+            .send(DispatchCmd::Request(SessionRequest::ChannelReq {
+                file,
+                start,
+                end,
+                timeout: self.request_timeout,
+                events,
+            }))
+            .ok()
+            .ok_or(Error::SessionDisconnected)?;
+        Ok(receiver)
+    }
+
+    /// Country code of the account, if the AP sent it already.
+    pub fn get_country_code(&self) -> Option<String> {
+        self.attributes.get().country_code
+    }
+
+    /// Block until the AP sends the country code of the account, at most for
+    /// `timeout`.
+    pub fn wait_for_country_code(&self, timeout: Duration) -> Option<String> {
+        self.attributes
+            .wait_until(|attrs| attrs.country_code.is_some(), timeout)?
+            .country_code
+    }
+
+    /// Snapshot of the account attributes received so far.
+    pub fn attributes(&self) -> SessionAttributes {
+        self.attributes.get()
+    }
+
+    /// Block until both the country code and the product info arrived, at most
+    /// for `timeout`.
+    pub fn wait_for_attributes(&self, timeout: Duration) -> Option<SessionAttributes> {
+        self.attributes
+            .wait_until(SessionAttributes::is_complete, timeout)
+    }
+
+    /// Receive the account attributes every time they change.
+    pub fn subscribe_attributes(&self) -> Receiver<SessionAttributes> {
+        self.attributes.subscribe()
     }
 
     pub fn request_shutdown(&self) {
@@ -260,12 +918,93 @@ impl SessionHandle {
     }
 }
 
+/// Payload of the first part of a GET response.
+fn first_part(response: MercuryResponse) -> Result<Bytes, Error> {
+    response
+        .payload
+        .into_iter()
+        .next()
+        .ok_or(Error::UnexpectedResponse)
+}
+
+/// Payloads of a multi-get request, in the order of the requested URIs.
+type MultiGetResults = Vec<Result<Vec<u8>, Error>>;
+
+/// Interpret the response to a multi-get request of `len` URIs.  Returns
+/// `None` if the server refused the batch, and the URIs should be fetched again
+/// one by one.
+fn multi_get_batch_results(
+    response: Result<MercuryResponse, Error>,
+    len: usize,
+) -> Result<Option<MultiGetResults>, Error> {
+    let batch = response
+        .and_then(MercuryResponse::error_for_status)
+        .and_then(MercuryResponse::into_multi_get_results);
+    match batch {
+        Ok(batch) if batch.len() == len => Ok(Some(batch)),
+        Ok(_) => {
+            log::warn!("multi-get reply does not match the request, fetching one by one");
+            Ok(None)
+        }
+        Err(Error::RequestTimedOut) => Ok(Some(
+            (0..len).map(|_| Err(Error::RequestTimedOut)).collect(),
+        )),
+        Err(Error::SessionDisconnected) => Err(Error::SessionDisconnected),
+        Err(err) => {
+            log::warn!("multi-get request failed, fetching one by one: {}", err);
+            Ok(None)
+        }
+    }
+}
+
+static SUBSCRIPTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Active subscription to Mercury events, created through
+/// `SessionHandle::subscribe_mercury`.  Subscriptions survive reconnects of
+/// the session, and are cancelled when dropped.
+pub struct MercurySubscription {
+    id: u64,
+    uri: String,
+    events: Receiver<MercuryResponse>,
+    sender: Sender<DispatchCmd>,
+}
+
+impl MercurySubscription {
+    fn fresh_id() -> u64 {
+        SUBSCRIPTION_ID.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Channel of the events published under the subscribed URI.  It gets
+    /// disconnected when the session terminates.
+    pub fn events(&self) -> &Receiver<MercuryResponse> {
+        &self.events
+    }
+}
+
+impl Drop for MercurySubscription {
+    fn drop(&mut self) {
+        let _ = self
+            .sender
+            .send(DispatchCmd::Request(SessionRequest::MercuryUnsub {
+                id: self.id,
+            }));
+    }
+}
+
 /// Read Shannon messages from the TCP stream one by one and send them to
 /// dispatcher for further processing.  In case the decoding fails with an error
 /// (this happens also in case we explicitly shutdown the connection), report
 /// the error to the dispatcher and quit.  If the dispatcher has already dropped
 /// its receiving part, quit silently as well.
-fn decode_shannon_messages(mut decoder: ShannonDecoder<TcpStream>, dispatch: Sender<DispatchCmd>) {
+fn decode_shannon_messages(
+    mut decoder: ShannonDecoder<TcpStream>,
+    dispatch: Sender<DispatchCmd>,
+    epoch: u64,
+) {
     loop {
         match decoder.decode() {
             Ok(msg) => {
@@ -273,8 +1012,8 @@ fn decode_shannon_messages(mut decoder: ShannonDecoder<TcpStream>, dispatch: Sen
                     break;
                 }
             }
-            Err(err) => {
-                let _ = dispatch.send(DispatchCmd::DecoderError(err));
+            Err(error) => {
+                let _ = dispatch.send(DispatchCmd::DecoderError { epoch, error });
                 break;
             }
         };
@@ -290,14 +1029,15 @@ fn encode_shannon_messages(
     mut encoder: ShannonEncoder<TcpStream>,
     messages: Receiver<ShannonMsg>,
     dispatch: Sender<DispatchCmd>,
+    epoch: u64,
 ) {
     for msg in messages {
         match encoder.encode(msg) {
             Ok(_) => {
                 // Message encoded, continue.
             }
-            Err(err) => {
-                let _ = dispatch.send(DispatchCmd::EncoderError(err));
+            Err(error) => {
+                let _ = dispatch.send(DispatchCmd::EncoderError { epoch, error });
                 break;
             }
         }
@@ -305,84 +1045,501 @@ fn encode_shannon_messages(
 }
 
 enum DispatchCmd {
+    Request(SessionRequest),
+    DecodedMsg(ShannonMsg),
+    DecoderError { epoch: u64, error: io::Error },
+    EncoderError { epoch: u64, error: io::Error },
+    Shutdown,
+}
+
+enum SessionRequest {
     MercuryReq {
         request: MercuryRequest,
-        callback: Sender<MercuryResponse>,
+        callback: ResponseCallback<MercuryResponse>,
+    },
+    MercurySub {
+        id: u64,
+        request: MercuryRequest,
+        events: Sender<MercuryResponse>,
+        callback: ResponseCallback<MercuryResponse>,
+    },
+    MercuryUnsub {
+        id: u64,
     },
     AudioKeyReq {
         track: ItemId,
         file: FileId,
-        callback: Sender<Result<AudioKey, Error>>,
+        callback: ResponseCallback<AudioKey>,
     },
-    CountryCodeReq {
-        callback: Sender<Option<String>>,
+    ChannelReq {
+        file: FileId,
+        start: u32,
+        end: u32,
+        timeout: Duration,
+        events: Sender<Result<ChannelEvent, Error>>,
     },
-    DecodedMsg(ShannonMsg),
-    DecoderError(io::Error),
-    EncoderError(io::Error),
+}
+
+// Delay before the first reconnection attempt, doubled after every failed one
+// up to `RECONNECT_MAX_DELAY`.
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const RECONNECT_MAX_ATTEMPTS: u32 = 10;
+
+/// Decoding and encoding threads of a single connection.  A new instance is
+/// started every time the session reconnects, and `epoch` lets the dispatcher
+/// tell errors of the current connection apart from late errors of a closed
+/// one.
+struct ConnectionIo {
+    epoch: u64,
+    stream: TcpStream,
+    messages: Sender<ShannonMsg>,
+    decoding_thread: JoinHandle<()>,
+    encoding_thread: JoinHandle<()>,
+}
+
+impl ConnectionIo {
+    fn start(epoch: u64, transport: Transport, dispatch: &Sender<DispatchCmd>) -> Self {
+        let (msg_send, msg_recv) = unbounded();
+        Self {
+            decoding_thread: {
+                let decoder = transport.decoder;
+                let dispatch = dispatch.clone();
+                thread::spawn(move || decode_shannon_messages(decoder, dispatch, epoch))
+            },
+            encoding_thread: {
+                let encoder = transport.encoder;
+                let dispatch = dispatch.clone();
+                thread::spawn(move || encode_shannon_messages(encoder, msg_recv, dispatch, epoch))
+            },
+            stream: transport.stream,
+            messages: msg_send,
+            epoch,
+        }
+    }
+
+    fn send(&self, msg: ShannonMsg) {
+        let _ = self.messages.send(msg);
+    }
+
+    /// Shut down the TCP stream and wait until both I/O threads quit.
+    fn close(self) {
+        let Self {
+            stream,
+            messages,
+            decoding_thread,
+            encoding_thread,
+            ..
+        } = self;
+        let _ = stream.shutdown(Shutdown::Both);
+        drop(messages);
+        if let Err(err) = encoding_thread.join() {
+            log::error!("session encoding thread panicked: {:?}", err);
+        }
+        if let Err(err) = decoding_thread.join() {
+            log::error!("session decoding thread panicked: {:?}", err);
+        }
+    }
+}
+
+enum Flow {
+    Continue,
+    Reconnect,
     Shutdown,
 }
 
-fn dispatch_messages(
+/// Liveness of the current connection, judged by the pings the AP sends.
+struct Keepalive {
+    last_ping: Instant,
+    // Wall-clock time of the last ping.  Unlike `Instant`, it keeps running
+    // while the machine is suspended.
+    last_ping_wall: SystemTime,
+    last_activity: Instant,
+}
+
+impl Keepalive {
+    fn new() -> Self {
+        Self {
+            last_ping: Instant::now(),
+            last_ping_wall: SystemTime::now(),
+            last_activity: Instant::now(),
+        }
+    }
+
+    fn record_activity(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    fn record_ping(&mut self) {
+        self.last_ping = Instant::now();
+        self.last_ping_wall = SystemTime::now();
+    }
+
+    fn is_overdue(&self) -> bool {
+        let since_wall = self.last_ping_wall.elapsed().unwrap_or_default();
+        self.last_ping.elapsed() > KEEPALIVE_TIMEOUT || since_wall > KEEPALIVE_TIMEOUT
+    }
+}
+
+/// State of the session that outlives the individual connections, i.e. the
+/// pending requests and the configuration used for reconnecting.
+struct SessionDispatcher {
     dispatch: Receiver<DispatchCmd>,
-    messages: Sender<ShannonMsg>,
-    stream: TcpStream,
-) {
-    let mut mercury = MercuryDispatcher::new();
-    let mut audio_key = AudioKeyDispatcher::new();
-    let mut country_code = None;
+    dispatch_send: Sender<DispatchCmd>,
+    config: SessionConfig,
+    dispatchers: Dispatchers,
+    refreshed_credentials: CredentialsSubscribers,
+    flags: WorkerFlags,
+    state: ConnectionStateStore,
+    keepalive: Keepalive,
+    epoch: u64,
+    next_sweep: Instant,
+}
+
+impl SessionDispatcher {
+    fn new(
+        dispatch: Receiver<DispatchCmd>,
+        dispatch_send: Sender<DispatchCmd>,
+        config: SessionConfig,
+        attributes: SessionAttributesStore,
+        refreshed_credentials: CredentialsSubscribers,
+        flags: WorkerFlags,
+        state: ConnectionStateStore,
+    ) -> Self {
+        Self {
+            dispatch,
+            dispatch_send,
+            config,
+            dispatchers: Dispatchers::new(attributes),
+            refreshed_credentials,
+            flags,
+            state,
+            keepalive: Keepalive::new(),
+            epoch: 0,
+            next_sweep: Instant::now() + REQUEST_SWEEP_INTERVAL,
+        }
+    }
 
-    for disp in dispatch {
+    fn run(mut self, transport: Transport) {
+        let mut io = self.start_io(transport);
+        loop {
+            let flow = match self.dispatch.recv_timeout(REQUEST_SWEEP_INTERVAL) {
+                Ok(disp) => self.handle(disp, &io),
+                Err(RecvTimeoutError::Timeout) => Flow::Continue,
+                Err(RecvTimeoutError::Disconnected) => Flow::Shutdown,
+            };
+            let flow = match flow {
+                Flow::Continue if self.keepalive.is_overdue() => {
+                    log::error!(
+                        "connection dead, no ping received, last activity {:?} ago",
+                        self.keepalive.last_activity.elapsed()
+                    );
+                    Flow::Reconnect
+                }
+                flow => flow,
+            };
+            for msg in self.sweep_expired() {
+                io.send(msg);
+            }
+            match flow {
+                Flow::Continue => {}
+                Flow::Reconnect => {
+                    self.flags.alive.store(false, Ordering::SeqCst);
+                    io.close();
+                    match self.reconnect() {
+                        Some(transport) => {
+                            io = self.start_io(transport);
+                            self.replay_pending(&io);
+                        }
+                        None => {
+                            break;
+                        }
+                    }
+                }
+                Flow::Shutdown => {
+                    io.close();
+                    self.state.set(ConnectionState::Disconnected);
+                    break;
+                }
+            }
+        }
+        // Dropping the dispatchers drops the callbacks of all pending requests,
+        // and the waiting callers receive `Error::SessionDisconnected`.
+    }
+
+    /// Periodically fail the pending requests past their deadline and forget
+    /// the cancelled ones.  Returns the messages to send to the AP.
+    fn sweep_expired(&mut self) -> Vec<ShannonMsg> {
+        let now = Instant::now();
+        if now < self.next_sweep {
+            return Vec::new();
+        }
+        self.next_sweep = now + REQUEST_SWEEP_INTERVAL;
+        self.dispatchers.sweep(now)
+    }
+
+    fn start_io(&mut self, transport: Transport) -> ConnectionIo {
+        self.epoch += 1;
+        self.keepalive = Keepalive::new();
+        self.flags.alive.store(true, Ordering::SeqCst);
+        ConnectionIo::start(self.epoch, transport, &self.dispatch_send)
+    }
+
+    fn handle(&mut self, disp: DispatchCmd, io: &ConnectionIo) -> Flow {
+        if let DispatchCmd::DecodedMsg(msg) = &disp {
+            self.keepalive.record_activity();
+            if msg.cmd == ShannonMsg::PING {
+                self.keepalive.record_ping();
+            }
+        }
         match disp {
-            DispatchCmd::MercuryReq { request, callback } => {
-                let msg = mercury.enqueue_request(request, callback);
-                let _ = messages.send(msg);
+            DispatchCmd::Request(request) => {
+                if let Some(msg) = self.dispatchers.enqueue(request) {
+                    io.send(msg);
+                }
             }
-            DispatchCmd::AudioKeyReq {
-                track,
-                file,
-                callback,
-            } => {
-                let msg = audio_key.enqueue_request(track, file, callback);
-                let _ = messages.send(msg);
+            DispatchCmd::DecodedMsg(msg) => {
+                if let Some(reply) = self.dispatchers.handle_msg(msg) {
+                    io.send(reply);
+                }
             }
-            DispatchCmd::CountryCodeReq { callback } => {
-                let _ = callback.send(country_code.clone());
+            DispatchCmd::DecoderError { epoch, error }
+            | DispatchCmd::EncoderError { epoch, error }
+                if epoch == io.epoch =>
+            {
+                log::error!("connection error: {:?}", error);
+                return Flow::Reconnect;
             }
-            DispatchCmd::DecodedMsg(msg) if msg.cmd == ShannonMsg::PING => {
-                let _ = messages.send(pong_message());
+            DispatchCmd::DecoderError { .. } | DispatchCmd::EncoderError { .. } => {
+                // Late error of an already closed connection, ignore.
             }
-            DispatchCmd::DecodedMsg(msg) if msg.cmd == ShannonMsg::COUNTRY_CODE => {
-                country_code.replace(parse_country_code(msg).unwrap());
+            DispatchCmd::Shutdown => {
+                log::info!("connection shutdown");
+                return Flow::Shutdown;
             }
-            DispatchCmd::DecodedMsg(msg) if msg.cmd == ShannonMsg::AES_KEY => {
-                audio_key.handle_aes_key(msg)
+        }
+        Flow::Continue
+    }
+
+    /// Try to open a new connection, with an exponential backoff between the
+    /// attempts.  Returns `None` if the attempts are exhausted, the failure is
+    /// not recoverable, or a shutdown was requested in the meantime.
+    fn reconnect(&mut self) -> Option<Transport> {
+        let mut backoff = Backoff::new(
+            RECONNECT_INITIAL_DELAY,
+            RECONNECT_MAX_DELAY,
+            RECONNECT_MAX_ATTEMPTS,
+        );
+        let mut last_err = None;
+        self.state.set(ConnectionState::Connecting);
+        while let Some(delay) = backoff.next_delay() {
+            log::info!("reconnecting in {:?}", delay);
+            if !self.wait_for_reconnect(delay) {
+                self.state.set(ConnectionState::Disconnected);
+                return None;
             }
-            DispatchCmd::DecodedMsg(msg) if msg.cmd == ShannonMsg::AES_KEY_ERROR => {
-                audio_key.handle_aes_key_error(msg)
+            let result = SessionConnection::open_with_state(self.config.clone(), &self.state);
+            if !self.flags.running.load(Ordering::SeqCst) {
+                // Shut down while connecting, drop the connection.
+                log::info!("connection shutdown");
+                return None;
             }
-            DispatchCmd::DecodedMsg(msg) if msg.cmd == ShannonMsg::MERCURY_REQ => {
-                mercury.handle_mercury_req(msg)
+            match result {
+                Ok(connection) => {
+                    log::info!("session reconnected");
+                    if connection.refreshed {
+                        publish_credentials(&self.refreshed_credentials, &connection.credentials);
+                    }
+                    self.config.login_creds = connection.credentials;
+                    self.dispatchers.attributes.set_welcome(&connection.welcome);
+                    return Some(connection.transport);
+                }
+                Err(err @ Error::AuthFailed { code }) if code != 2 => {
+                    // Only "try another AP" is worth retrying, other failures
+                    // mean our credentials are not accepted anymore.
+                    log::error!("reconnect failed: {}", err);
+                    self.state.set(ConnectionState::Failed {
+                        error: err.to_string(),
+                    });
+                    return None;
+                }
+                Err(err) => {
+                    log::warn!("reconnect failed: {}", err);
+                    self.state.set(ConnectionState::Connecting);
+                    last_err.replace(err);
+                }
             }
-            DispatchCmd::DecodedMsg(msg) => {
-                log::debug!("ignored message: {:?}", msg.cmd);
+        }
+        log::error!("giving up reconnecting");
+        self.state.set(ConnectionState::Failed {
+            error: last_err.unwrap_or(Error::SessionDisconnected).to_string(),
+        });
+        None
+    }
+
+    /// Keep servicing dispatch commands until `delay` passes.  Requests are
+    /// enqueued and sent after reconnecting in `replay_pending`.  Returns
+    /// `false` if a shutdown was requested.
+    fn wait_for_reconnect(&mut self, delay: Duration) -> bool {
+        let deadline = Instant::now() + delay;
+        loop {
+            if !self.flags.running.load(Ordering::SeqCst) {
+                return false;
             }
-            DispatchCmd::DecoderError(err) => {
-                log::error!("connection error: {:?}", err);
-                let _ = stream.shutdown(Shutdown::Write);
-                break;
+            // The timed out channels are not requested again after reconnecting,
+            // so there is nothing to abort.
+            self.sweep_expired();
+            match self.dispatch.recv_deadline(deadline.min(self.next_sweep)) {
+                Ok(DispatchCmd::Request(request)) => {
+                    self.dispatchers.enqueue(request);
+                }
+                Ok(
+                    DispatchCmd::DecodedMsg(_)
+                    | DispatchCmd::DecoderError { .. }
+                    | DispatchCmd::EncoderError { .. },
+                ) => {
+                    // Leftovers from the closed connection, ignore.
+                }
+                Ok(DispatchCmd::Shutdown) => {
+                    log::info!("connection shutdown");
+                    return false;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if Instant::now() >= deadline {
+                        return true;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return false;
+                }
             }
-            DispatchCmd::EncoderError(err) => {
-                log::error!("connection error: {:?}", err);
-                let _ = stream.shutdown(Shutdown::Read);
-                break;
+        }
+    }
+
+    fn replay_pending(&mut self, io: &ConnectionIo) {
+        for msg in self.dispatchers.requeue_pending() {
+            io.send(msg);
+        }
+    }
+}
+
+/// Pending requests of a session and the handling of the messages the AP
+/// sends, shared by the blocking `SessionDispatcher` and the async dispatcher.
+struct Dispatchers {
+    mercury: MercuryDispatcher,
+    audio_key: AudioKeyDispatcher,
+    channel: ChannelDispatcher,
+    attributes: SessionAttributesStore,
+}
+
+impl Dispatchers {
+    fn new(attributes: SessionAttributesStore) -> Self {
+        Self {
+            mercury: MercuryDispatcher::new(),
+            audio_key: AudioKeyDispatcher::new(),
+            channel: ChannelDispatcher::new(),
+            attributes,
+        }
+    }
+
+    /// Register `request`, and return the message to send to the AP.
+    fn enqueue(&mut self, request: SessionRequest) -> Option<ShannonMsg> {
+        match request {
+            SessionRequest::MercuryReq { request, callback } => {
+                Some(self.mercury.enqueue_request(request, callback))
             }
-            DispatchCmd::Shutdown => {
-                log::info!("connection shutdown");
-                let _ = stream.shutdown(Shutdown::Both);
-                break;
+            SessionRequest::MercurySub {
+                id,
+                request,
+                events,
+                callback,
+            } => Some(self.mercury.subscribe(id, request, events, callback)),
+            SessionRequest::MercuryUnsub { id } => self.mercury.unsubscribe(id),
+            SessionRequest::AudioKeyReq {
+                track,
+                file,
+                callback,
+            } => Some(self.audio_key.enqueue_request(track, file, callback)),
+            SessionRequest::ChannelReq {
+                file,
+                start,
+                end,
+                timeout,
+                events,
+            } => Some(
+                self.channel
+                    .enqueue_request(file, start, end, timeout, events),
+            ),
+        }
+    }
+
+    /// Handle a message received from the AP, and return the reply to send
+    /// back, if any.
+    fn handle_msg(&mut self, msg: ShannonMsg) -> Option<ShannonMsg> {
+        match msg.cmd {
+            ShannonMsg::PING => {
+                return Some(pong_message());
+            }
+            ShannonMsg::PONG_ACK => {
+                log::trace!("pong acknowledged");
+            }
+            ShannonMsg::COUNTRY_CODE => match parse_country_code(msg) {
+                Ok(country_code) => {
+                    self.attributes.update(|attrs| {
+                        attrs.country_code.replace(country_code);
+                    });
+                }
+                Err(err) => {
+                    log::error!("failed to parse country code: {}", err);
+                }
+            },
+            ShannonMsg::PRODUCT_INFO => match std::str::from_utf8(&msg.payload) {
+                Ok(xml) => {
+                    let product = parse_product_info(xml);
+                    self.attributes.update(|attrs| attrs.product = product);
+                }
+                Err(err) => {
+                    log::error!("failed to parse product info: {}", err);
+                }
+            },
+            ShannonMsg::SECRET_BLOCK | ShannonMsg::LEGACY_WELCOME | ShannonMsg::LICENSE_VERSION => {
+                // Sent after login, but carry nothing we need.
+                log::trace!("ignored login message: {:?}", msg.cmd);
+            }
+            ShannonMsg::AES_KEY => self.audio_key.handle_aes_key(msg),
+            ShannonMsg::AES_KEY_ERROR => self.audio_key.handle_aes_key_error(msg),
+            ShannonMsg::STREAM_CHUNK_RES => {
+                return self.channel.handle_stream_chunk_res(msg);
+            }
+            ShannonMsg::CHANNEL_ERROR => self.channel.handle_channel_error(msg),
+            ShannonMsg::CHANNEL_ABORT => self.channel.handle_channel_abort(msg),
+            ShannonMsg::MERCURY_REQ | ShannonMsg::MERCURY_SUB | ShannonMsg::MERCURY_UNSUB => {
+                self.mercury.handle_mercury_req(msg)
+            }
+            ShannonMsg::MERCURY_PUB => self.mercury.handle_mercury_pub(msg),
+            _ => {
+                log::debug!("ignored message: {:?}", msg.cmd);
             }
         }
+        None
+    }
+
+    /// Fail the pending requests past their deadline and forget the cancelled
+    /// ones.  Returns the messages aborting the timed out channels.
+    fn sweep(&mut self, now: Instant) -> Vec<ShannonMsg> {
+        self.mercury.sweep(now);
+        self.audio_key.sweep(now);
+        self.channel.sweep(now)
+    }
+
+    /// Messages re-issuing all pending requests and subscriptions on a new
+    /// connection.
+    fn requeue_pending(&mut self) -> Vec<ShannonMsg> {
+        let mut msgs = self.mercury.requeue_pending();
+        msgs.extend(self.mercury.resubscribe());
+        msgs.extend(self.audio_key.requeue_pending());
+        msgs.extend(self.channel.requeue_pending());
+        msgs
     }
 }
 
@@ -391,7 +1548,7 @@ fn pong_message() -> ShannonMsg {
 }
 
 fn parse_country_code(msg: ShannonMsg) -> Result<String, Error> {
-    String::from_utf8(msg.payload)
+    String::from_utf8(msg.payload.into())
         .ok()
         .ok_or(Error::UnexpectedResponse)
 }
@@ -0,0 +1,168 @@
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "async")]
+use crossbeam_channel::TryRecvError;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+#[cfg(feature = "async")]
+use tokio::sync::Notify;
+
+use crate::error::Error;
+
+use super::DEFAULT_REQUEST_TIMEOUT;
+
+/// Make a connected pair of a dispatcher-side callback and a caller-side
+/// pending response, both sharing a deadline `timeout` from now.
+pub fn response_channel<T>(timeout: Duration) -> (ResponseCallback<T>, PendingResponse<T>) {
+    let (sender, receiver) = bounded(1);
+    let deadline = Instant::now() + timeout;
+    let alive = Arc::new(());
+    (
+        ResponseCallback {
+            sender,
+            deadline,
+            receiver_alive: Some(Arc::downgrade(&alive)),
+            #[cfg(feature = "async")]
+            notify: None,
+        },
+        PendingResponse {
+            receiver,
+            deadline,
+            _alive: alive,
+        },
+    )
+}
+
+/// Dispatcher side of a request.  Knows where to deliver the response, until
+/// when, and whether anybody is still waiting for it.
+#[derive(Debug)]
+pub struct ResponseCallback<T> {
+    sender: Sender<Result<T, Error>>,
+    deadline: Instant,
+    receiver_alive: Option<Weak<()>>,
+    /// Wakes up the `AsyncPendingResponse`, if the caller awaits one.
+    #[cfg(feature = "async")]
+    notify: Option<Arc<Notify>>,
+}
+
+impl<T> ResponseCallback<T> {
+    /// Callback of a request nobody waits for, i.e. it is never considered
+    /// cancelled, and its response is thrown away.
+    pub fn detached() -> Self {
+        let (sender, _) = bounded(1);
+        Self {
+            sender,
+            deadline: Instant::now() + DEFAULT_REQUEST_TIMEOUT,
+            receiver_alive: None,
+            #[cfg(feature = "async")]
+            notify: None,
+        }
+    }
+
+    /// Deliver the result.  Fails if the caller is gone already.
+    pub fn send(&self, result: Result<T, Error>) -> Result<(), Error> {
+        self.sender.try_send(result).map_err(|_| Error::SendError)?;
+        #[cfg(feature = "async")]
+        if let Some(notify) = &self.notify {
+            notify.notify_one();
+        }
+        Ok(())
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    /// Returns true if the caller has dropped its `PendingResponse`.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(&self.receiver_alive, Some(alive) if alive.strong_count() == 0)
+    }
+}
+
+/// Caller side of a request sent to the session.  Dropping it before the
+/// response arrives cancels the request.
+pub struct PendingResponse<T> {
+    receiver: Receiver<Result<T, Error>>,
+    deadline: Instant,
+    _alive: Arc<()>,
+}
+
+impl<T> PendingResponse<T> {
+    /// Block until the response arrives, or the request deadline passes.
+    pub fn wait(self) -> Result<T, Error> {
+        match self.receiver.recv_deadline(self.deadline) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(Error::RequestTimedOut),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::SessionDisconnected),
+        }
+    }
+}
+
+/// Like `response_channel`, but the caller side can be awaited.
+#[cfg(feature = "async")]
+pub fn async_response_channel<T>(
+    timeout: Duration,
+) -> (ResponseCallback<T>, AsyncPendingResponse<T>) {
+    let (sender, receiver) = bounded(1);
+    let deadline = Instant::now() + timeout;
+    let alive = Arc::new(());
+    let notify = Arc::new(Notify::new());
+    (
+        ResponseCallback {
+            sender,
+            deadline,
+            receiver_alive: Some(Arc::downgrade(&alive)),
+            notify: Some(notify.clone()),
+        },
+        AsyncPendingResponse {
+            receiver,
+            deadline,
+            notify,
+            _alive: alive,
+        },
+    )
+}
+
+#[cfg(feature = "async")]
+impl<T> Drop for ResponseCallback<T> {
+    fn drop(&mut self) {
+        // Let the awaiting caller notice the dispatcher gave up on the request.
+        if let Some(notify) = &self.notify {
+            notify.notify_one();
+        }
+    }
+}
+
+/// Like `PendingResponse`, but awaited instead of blocking the thread.
+#[cfg(feature = "async")]
+pub struct AsyncPendingResponse<T> {
+    receiver: Receiver<Result<T, Error>>,
+    deadline: Instant,
+    notify: Arc<Notify>,
+    _alive: Arc<()>,
+}
+
+#[cfg(feature = "async")]
+impl<T> AsyncPendingResponse<T> {
+    /// Wait until the response arrives, or the request deadline passes.
+    pub async fn wait(self) -> Result<T, Error> {
+        let deadline = tokio::time::Instant::from_std(self.deadline);
+        loop {
+            match self.receiver.try_recv() {
+                Ok(result) => return result,
+                Err(TryRecvError::Disconnected) => return Err(Error::SessionDisconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            // A notification sent since the last check is stored as a permit, so
+            // it cannot get lost.
+            if tokio::time::timeout_at(deadline, self.notify.notified())
+                .await
+                .is_err()
+            {
+                return Err(Error::RequestTimedOut);
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use parking_lot::Mutex;
+
+/// Phase of the session connection, as published by `SessionService`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Authenticating,
+    Connected {
+        ap: String,
+        username: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Self::Connected { .. })
+    }
+}
+
+/// Shared, observable `ConnectionState`.  Cheap to clone.
+#[derive(Clone, Default)]
+pub struct ConnectionStateStore {
+    shared: Arc<Shared>,
+    /// If set, changes are only published while it is true, see `gated`.
+    gate: Option<Arc<AtomicBool>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<ConnectionState>,
+    subscribers: Mutex<Vec<Sender<ConnectionState>>>,
+}
+
+impl ConnectionStateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> ConnectionState {
+        self.shared.state.lock().clone()
+    }
+
+    /// Receive the state every time it changes.
+    pub fn subscribe(&self) -> Receiver<ConnectionState> {
+        let (sender, receiver) = unbounded();
+        self.shared.subscribers.lock().push(sender);
+        receiver
+    }
+
+    /// Return a handle to the same state, that ignores all changes once
+    /// `open` is cleared.
+    pub fn gated(&self, open: Arc<AtomicBool>) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            gate: Some(open),
+        }
+    }
+
+    pub fn set(&self, state: ConnectionState) {
+        if matches!(&self.gate, Some(open) if !open.load(Ordering::SeqCst)) {
+            return;
+        }
+        {
+            let mut current = self.shared.state.lock();
+            if *current == state {
+                return;
+            }
+            log::debug!("connection state: {:?}", state);
+            *current = state.clone();
+        }
+        self.shared
+            .subscribers
+            .lock()
+            .retain(|sender| sender.send(state.clone()).is_ok());
+    }
+}
@@ -1,9 +1,15 @@
-use std::{io, io::SeekFrom, mem, time::Duration};
+use std::{
+    io::{self, Cursor, SeekFrom},
+    mem,
+    time::Duration,
+};
 
+use bytes::Bytes;
 use num_traits::{One, WrappingAdd};
 use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
+use rand::Rng;
 
-use crate::error::Error;
+use crate::{connection::proxy::parse_proxy_url, error::Error};
 
 pub const NET_CONNECT_TIMEOUT: Duration = Duration::from_millis(8 * 1000);
 
@@ -15,7 +21,9 @@ pub fn default_ureq_agent_builder(proxy_url: Option<&str>) -> Result<ureq::Agent
         .timeout_read(NET_IO_TIMEOUT)
         .timeout_write(NET_IO_TIMEOUT);
     if let Some(url) = proxy_url {
-        let proxy = ureq::Proxy::new(url)?;
+        // Parsed like the AP proxy URL, so both accept the same ones.
+        let url = parse_proxy_url(url)?;
+        let proxy = ureq::Proxy::new(url.as_str())?;
         Ok(builder.proxy(proxy))
     } else {
         Ok(builder)
@@ -36,6 +44,47 @@ impl<T: One + WrappingAdd> Sequence<T> {
     }
 }
 
+/// Exponential backoff with a random jitter, used to space out retries of
+/// failing network operations.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: u32,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, max_attempts: u32) -> Self {
+        Self {
+            initial,
+            max,
+            max_attempts,
+            attempt: 0,
+        }
+    }
+
+    /// Return the delay to wait before the next attempt, or `None` if all
+    /// attempts have been used up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempt >= self.max_attempts {
+            return None;
+        }
+        let delay = self
+            .initial
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+        // Randomize the delay, so many clients dropped at the same time do not
+        // retry in lockstep.
+        Some(delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0)))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
 pub struct OffsetFile<T> {
     stream: T,
     offset: u64,
@@ -121,6 +170,17 @@ where
     }
 }
 
+/// Read the next `len` bytes of `cursor` as a slice sharing its buffer.
+pub fn read_bytes(cursor: &mut Cursor<Bytes>, len: usize) -> io::Result<Bytes> {
+    let start = cursor.position() as usize;
+    let end = start.saturating_add(len);
+    if end > cursor.get_ref().len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    cursor.set_position(end as u64);
+    Ok(cursor.get_ref().slice(start..end))
+}
+
 pub fn serialize_protobuf<T>(msg: &T) -> Result<Vec<u8>, Error>
 where
     T: MessageWrite,
@@ -0,0 +1,59 @@
+use psst_core::{
+    audio::decrypt::AudioKey,
+    connection::{
+        mock_ap::{AudioKeyReply, MercuryReply, MockAp},
+        Credentials,
+    },
+    error::Error,
+    item_id::{FileId, ItemId, ItemIdType},
+    session::{async_session::AsyncSessionWorker, mercury::MercuryRequest},
+};
+
+async fn start_worker(ap: &MockAp) -> AsyncSessionWorker {
+    let credentials = Credentials::from_username_and_password("user".into(), "password".into());
+    AsyncSessionWorker::connect(&ap.session_config(credentials))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn answers_mercury_requests() {
+    let ap = MockAp::start().unwrap();
+    let payload = vec![vec![7_u8; 1000], b"small".to_vec()];
+    ap.set_mercury_reply("hm://test/get", MercuryReply::ok(payload.clone()));
+
+    let worker = start_worker(&ap).await;
+    let handle = worker.handle();
+    let response = handle
+        .mercury_request(MercuryRequest::get("hm://test/get".into()))
+        .await
+        .unwrap();
+    assert_eq!(response.payload, payload);
+    assert!(matches!(
+        handle.get_mercury_bytes("hm://test/missing".into()).await,
+        Err(Error::MercuryNotFound)
+    ));
+    handle.request_shutdown();
+    worker.join().await;
+}
+
+#[tokio::test]
+async fn answers_audio_keys() {
+    let ap = MockAp::start().unwrap();
+    let track = ItemId::new(1, ItemIdType::Track);
+    let key = AudioKey([3; 16]);
+    ap.set_audio_key_reply(FileId([1; 20]), AudioKeyReply::Key(key));
+
+    let worker = start_worker(&ap).await;
+    let handle = worker.handle();
+    assert_eq!(
+        handle.get_audio_key(track, FileId([1; 20])).await.unwrap(),
+        key
+    );
+    assert!(matches!(
+        handle.get_audio_key(track, FileId([2; 20])).await,
+        Err(Error::UnexpectedResponse)
+    ));
+    handle.request_shutdown();
+    worker.join().await;
+}
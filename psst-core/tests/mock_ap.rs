@@ -0,0 +1,212 @@
+use std::{thread, time::Duration};
+
+use psst_core::{
+    audio::decrypt::AudioKey,
+    connection::{
+        mock_ap::{AudioKeyReply, LoginReply, MercuryReply, MockAp, MockRequest},
+        Credentials,
+    },
+    error::Error,
+    item_id::{FileId, ItemId, ItemIdType},
+    session::{
+        mercury::MercuryRequest,
+        state::{ConnectionState, ConnectionStateStore},
+        SessionConnection, SessionWorker,
+    },
+};
+use psst_protocol::keyexchange::ErrorCode;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn credentials() -> Credentials {
+    Credentials::from_username_and_password("user".into(), "password".into())
+}
+
+fn start_worker(ap: &MockAp) -> SessionWorker {
+    let config = ap.session_config(credentials());
+    let connection = SessionConnection::open(config.clone()).unwrap();
+    SessionWorker::run(connection, config, ConnectionStateStore::new())
+}
+
+fn shut_down(worker: SessionWorker) {
+    worker.handle().request_shutdown();
+    worker.join();
+}
+
+#[test]
+fn answers_mercury_requests_split_across_frames() {
+    let ap = MockAp::start().unwrap();
+    ap.set_country_code("SE");
+    ap.set_mercury_frame_size(64);
+    let payload = vec![vec![7_u8; 1000], b"small".to_vec()];
+    ap.set_mercury_reply("hm://test/split", MercuryReply::ok(payload.clone()));
+
+    let worker = start_worker(&ap);
+    let handle = worker.handle();
+    let response = handle
+        .mercury_request(MercuryRequest::get("hm://test/split".into()))
+        .unwrap();
+    assert_eq!(response.payload, payload);
+    assert_eq!(handle.wait_for_country_code(TIMEOUT).as_deref(), Some("SE"));
+    assert!(matches!(
+        handle.mercury_request(MercuryRequest::get("hm://test/missing".into())),
+        Err(Error::MercuryNotFound)
+    ));
+    shut_down(worker);
+}
+
+#[test]
+fn reports_rejected_credentials() {
+    let ap = MockAp::start().unwrap();
+    ap.push_login_reply(LoginReply::Failed(ErrorCode::BadCredentials));
+
+    let result = SessionConnection::open(ap.session_config(credentials()));
+    assert!(matches!(result, Err(Error::AuthFailed { code: 12 })));
+}
+
+#[test]
+fn solves_hash_cash_challenge() {
+    let ap = MockAp::start().unwrap();
+    ap.set_hash_cash(16, 8);
+
+    let connection = SessionConnection::open(ap.session_config(credentials())).unwrap();
+    assert_eq!(connection.welcome.canonical_username, "user");
+}
+
+#[test]
+fn answers_audio_keys() {
+    let ap = MockAp::start().unwrap();
+    let track = ItemId::new(1, ItemIdType::Track);
+    let key = AudioKey([3; 16]);
+    ap.set_audio_key_reply(FileId([1; 20]), AudioKeyReply::Key(key));
+
+    let worker = start_worker(&ap);
+    let handle = worker.handle();
+    assert_eq!(handle.get_audio_key(track, FileId([1; 20])).unwrap(), key);
+    assert!(matches!(
+        handle.get_audio_key(track, FileId([2; 20])),
+        Err(Error::UnexpectedResponse)
+    ));
+    shut_down(worker);
+}
+
+#[test]
+fn times_out_unanswered_requests() {
+    let ap = MockAp::start().unwrap();
+    ap.set_mercury_reply("hm://test/silent", MercuryReply::Silent);
+
+    let worker = start_worker(&ap);
+    let request = MercuryRequest::get("hm://test/silent".into());
+    let result = worker
+        .handle()
+        .mercury_request_with_timeout(request, Duration::from_millis(100));
+    assert!(matches!(result, Err(Error::RequestTimedOut)));
+    shut_down(worker);
+}
+
+#[test]
+fn replies_to_pings() {
+    let ap = MockAp::start().unwrap();
+    let worker = start_worker(&ap);
+    ap.send_ping();
+    assert!(ap
+        .wait_for_request(TIMEOUT, |req| req.request == MockRequest::Pong)
+        .is_some());
+    shut_down(worker);
+}
+
+#[test]
+fn replays_pending_requests_after_reconnecting() {
+    let ap = MockAp::start().unwrap();
+    let uri = "hm://test/replayed";
+    ap.set_mercury_reply(uri, MercuryReply::Silent);
+
+    let worker = start_worker(&ap);
+    let handle = worker.handle();
+    let request = thread::spawn(move || handle.mercury_request(MercuryRequest::get(uri.into())));
+    ap.wait_for_request(
+        TIMEOUT,
+        |req| matches!(&req.request, MockRequest::Mercury { uri: req_uri, .. } if req_uri == uri),
+    )
+    .unwrap();
+
+    // Answer only the request sent over the next connection.
+    ap.set_mercury_reply(uri, MercuryReply::ok(vec![b"replayed".to_vec()]));
+    ap.disconnect();
+    let response = request.join().unwrap().unwrap();
+    assert_eq!(response.payload, vec![b"replayed".to_vec()]);
+
+    // The session logs in again with the reusable credentials.
+    let login = ap
+        .wait_for_request(TIMEOUT, |req| {
+            req.connection == 1 && matches!(req.request, MockRequest::Login { .. })
+        })
+        .unwrap();
+    assert!(matches!(
+        login.request,
+        MockRequest::Login { auth_data, .. } if auth_data == b"mock-reusable-credentials"
+    ));
+    shut_down(worker);
+}
+
+#[test]
+fn fails_interrupted_send_requests_instead_of_replaying_them() {
+    let ap = MockAp::start().unwrap();
+    let uri = "hm://test/send";
+    ap.set_mercury_reply(uri, MercuryReply::Silent);
+
+    let worker = start_worker(&ap);
+    let handle = worker.handle();
+    let request =
+        thread::spawn(move || handle.mercury_request(MercuryRequest::send(uri.into(), vec![1])));
+    ap.wait_for_request(
+        TIMEOUT,
+        |req| matches!(&req.request, MockRequest::Mercury { uri: req_uri, .. } if req_uri == uri),
+    )
+    .unwrap();
+
+    ap.disconnect();
+    assert!(matches!(
+        request.join().unwrap(),
+        Err(Error::SessionDisconnected)
+    ));
+    assert!(ap.wait_for_connections(2, TIMEOUT));
+    assert!(!ap.requests().iter().any(|req| req.connection == 1
+        && matches!(&req.request, MockRequest::Mercury { uri: req_uri, .. } if req_uri == uri)));
+    shut_down(worker);
+}
+
+#[test]
+fn reconnects_after_protocol_error() {
+    let ap = MockAp::start().unwrap();
+    let worker = start_worker(&ap);
+    // Enough data for a whole frame, failing the MAC check.
+    ap.send_garbage(&[0xff; 70_000]);
+    assert!(ap.wait_for_connections(2, TIMEOUT));
+    shut_down(worker);
+}
+
+#[test]
+fn shuts_down_while_reconnecting() {
+    let ap = MockAp::start().unwrap();
+    let state = ConnectionStateStore::new();
+    let config = ap.session_config(credentials());
+    let connection = SessionConnection::open(config.clone()).unwrap();
+    let worker = SessionWorker::run(connection, config, state.clone());
+
+    // Leave the login of the reconnect attempt unanswered.
+    ap.push_login_reply(LoginReply::Silent);
+    ap.disconnect();
+    ap.wait_for_request(TIMEOUT, |req| {
+        req.connection == 1 && matches!(req.request, MockRequest::Login { .. })
+    })
+    .unwrap();
+    worker.request_shutdown();
+    assert_eq!(state.get(), ConnectionState::Disconnected);
+
+    // The attempt in progress fails, but is not retried.
+    ap.disconnect();
+    worker.join();
+    assert_eq!(state.get(), ConnectionState::Disconnected);
+    assert_eq!(ap.connection_count(), 2);
+}
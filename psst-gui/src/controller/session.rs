@@ -1,10 +1,41 @@
-use druid::{widget::Controller, Event, EventCtx, LifeCycle, Widget};
+use std::thread::{self, JoinHandle};
+
+use druid::{widget::Controller, Event, EventCtx, LifeCycle, Selector, Widget};
+use psst_core::{connection::Credentials, session::state::ConnectionState};
 
 use crate::{cmd, data::AppState, ui::playlist};
 
-pub struct SessionController;
+#[derive(Default)]
+pub struct SessionController {
+    state_thread: Option<JoinHandle<()>>,
+    credentials_thread: Option<JoinHandle<()>>,
+}
 
 impl SessionController {
+    const STATE_CHANGED: Selector<ConnectionState> =
+        Selector::new("app.session.connection-state-changed");
+    const CREDENTIALS_REFRESHED: Selector<Credentials> =
+        Selector::new("app.session.credentials-refreshed");
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forward the session connection state changes into the app data, and
+    /// the refreshed credentials into the config.
+    fn watch_session(&mut self, ctx: &mut EventCtx, data: &AppState) {
+        self.state_thread.replace(forward_to_widget(
+            ctx,
+            data.session.subscribe_connection_state(),
+            Self::STATE_CHANGED,
+        ));
+        self.credentials_thread.replace(forward_to_widget(
+            ctx,
+            data.session.subscribe_refreshed_credentials(),
+            Self::CREDENTIALS_REFRESHED,
+        ));
+    }
+
     fn connect(&self, ctx: &mut EventCtx, data: &mut AppState) {
         // Update the session configuration, any active session will get shut down.
         data.session.update_config(data.config.session());
@@ -28,7 +59,20 @@ where
         env: &druid::Env,
     ) {
         match event {
+            Event::Command(cmd) if cmd.is(Self::STATE_CHANGED) => {
+                data.connection_state = cmd.get_unchecked(Self::STATE_CHANGED).clone();
+                ctx.set_handled();
+            }
+            Event::Command(cmd) if cmd.is(Self::CREDENTIALS_REFRESHED) => {
+                let credentials = cmd.get_unchecked(Self::CREDENTIALS_REFRESHED).clone();
+                data.config.store_credentials(credentials);
+                data.config.save();
+                ctx.set_handled();
+            }
             Event::Command(cmd) if cmd.is(cmd::SESSION_CONNECT) => {
+                if self.state_thread.is_none() {
+                    self.watch_session(ctx, data);
+                }
                 if data.config.has_credentials() {
                     self.connect(ctx, data);
                 }
@@ -54,3 +98,23 @@ where
         child.lifecycle(ctx, event, data, env);
     }
 }
+
+/// Submit every value received from `receiver` to the widget of `ctx`.
+fn forward_to_widget<T: Send + 'static>(
+    ctx: &mut EventCtx,
+    receiver: impl IntoIterator<Item = T> + Send + 'static,
+    selector: Selector<T>,
+) -> JoinHandle<()> {
+    let event_sink = ctx.get_external_handle();
+    let widget_id = ctx.widget_id();
+    thread::spawn(move || {
+        for value in receiver {
+            if event_sink
+                .submit_command(selector, value, widget_id)
+                .is_err()
+            {
+                break;
+            }
+        }
+    })
+}
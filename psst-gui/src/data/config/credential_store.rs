@@ -0,0 +1,290 @@
+use std::{
+    error, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit,
+};
+use psst_core::{cache::mkdir_if_not_exists, connection::Credentials};
+use rand::RngCore;
+
+/// Persistent storage of the login credentials, kept apart from the rest of
+/// the configuration.
+pub trait CredentialStore: Send + Sync {
+    /// Human-readable name of the backend, used for logging.
+    fn name(&self) -> &'static str;
+
+    fn load(&self) -> Result<Option<Credentials>, CredentialStoreError>;
+
+    fn store(&self, credentials: &Credentials) -> Result<(), CredentialStoreError>;
+
+    fn clear(&self) -> Result<(), CredentialStoreError>;
+}
+
+#[derive(Debug)]
+pub enum CredentialStoreError {
+    Keyring(keyring::Error),
+    Io(io::Error),
+    Serialization(serde_json::Error),
+    KeyDerivation(argon2::Error),
+    /// The file is damaged, or was encrypted with a different key.
+    Decryption,
+}
+
+impl error::Error for CredentialStoreError {}
+
+impl fmt::Display for CredentialStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Keyring(err) => write!(f, "Keyring error: {}", err),
+            Self::Io(err) => write!(f, "I/O error: {}", err),
+            Self::Serialization(err) => write!(f, "Invalid credentials: {}", err),
+            Self::KeyDerivation(err) => write!(f, "Failed to derive key: {}", err),
+            Self::Decryption => write!(f, "Failed to decrypt credentials"),
+        }
+    }
+}
+
+impl From<keyring::Error> for CredentialStoreError {
+    fn from(err: keyring::Error) -> Self {
+        Self::Keyring(err)
+    }
+}
+
+impl From<io::Error> for CredentialStoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for CredentialStoreError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Serialization(err)
+    }
+}
+
+impl From<argon2::Error> for CredentialStoreError {
+    fn from(err: argon2::Error) -> Self {
+        Self::KeyDerivation(err)
+    }
+}
+
+/// Stores the credentials in the system keyring, i.e. the Secret Service on
+/// Linux, the Keychain on macOS and the Credential Manager on Windows.
+pub struct KeyringStore {
+    entry: keyring::Entry,
+}
+
+impl KeyringStore {
+    /// Returns `None` if the keyring is not reachable, e.g. when there is no
+    /// Secret Service running on the session bus.
+    pub fn open(service: &str, user: &str) -> Option<Self> {
+        let entry = keyring::Entry::new(service, user)
+            .inspect_err(|err| log::info!("keyring not available: {}", err))
+            .ok()?;
+        match entry.get_password() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Some(Self { entry }),
+            Err(err) => {
+                log::info!("keyring not available: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl CredentialStore for KeyringStore {
+    fn name(&self) -> &'static str {
+        "keyring"
+    }
+
+    fn load(&self) -> Result<Option<Credentials>, CredentialStoreError> {
+        match self.entry.get_password() {
+            Ok(secret) => Ok(Some(serde_json::from_str(&secret)?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn store(&self, credentials: &Credentials) -> Result<(), CredentialStoreError> {
+        let secret = serde_json::to_string(credentials)?;
+        self.entry.set_password(&secret)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), CredentialStoreError> {
+        match self.entry.delete_credential() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Where the key of the `EncryptedFileStore` comes from.
+pub enum KeySource {
+    /// Derive the key from a passphrase with Argon2id.
+    Passphrase(String),
+    /// Read the key from a file, generating a random one first if it does not
+    /// exist.  Protects the credentials only if the file is kept out of their
+    /// directory, so that a copy of that directory (e.g. in a backup) does not
+    /// include the key.  Anybody able to read both files can decrypt them.
+    KeyFile(PathBuf),
+}
+
+// Header of the encrypted credentials file, followed by a format version byte.
+const FILE_MAGIC: &[u8] = b"PSSTCRED";
+const FILE_VERSION: u8 = 1;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = FILE_MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+/// Stores the credentials in a file encrypted with ChaCha20-Poly1305.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    key: KeySource,
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: PathBuf, key: KeySource) -> Self {
+        Self { path, key }
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Result<ChaCha20Poly1305, CredentialStoreError> {
+        let mut key = [0_u8; KEY_LEN];
+        match &self.key {
+            KeySource::Passphrase(passphrase) => {
+                Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+            }
+            KeySource::KeyFile(path) => {
+                key = load_or_create_key_file(path)?;
+            }
+        }
+        Ok(ChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+impl CredentialStore for EncryptedFileStore {
+    fn name(&self) -> &'static str {
+        "encrypted file"
+    }
+
+    fn load(&self) -> Result<Option<Credentials>, CredentialStoreError> {
+        let file = match fs::read(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if file.len() < HEADER_LEN
+            || !file.starts_with(FILE_MAGIC)
+            || file[FILE_MAGIC.len()] != FILE_VERSION
+        {
+            return Err(CredentialStoreError::Decryption);
+        }
+        let (header, ciphertext) = file.split_at(HEADER_LEN);
+        let salt = &header[FILE_MAGIC.len() + 1..][..SALT_LEN];
+        let nonce = &header[HEADER_LEN - NONCE_LEN..];
+        let plaintext = self
+            .cipher(salt)?
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| CredentialStoreError::Decryption)?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+
+    fn store(&self, credentials: &Credentials) -> Result<(), CredentialStoreError> {
+        let mut salt = [0_u8; SALT_LEN];
+        let mut nonce = [0_u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut file = Vec::with_capacity(HEADER_LEN);
+        file.extend(FILE_MAGIC);
+        file.push(FILE_VERSION);
+        file.extend(salt);
+        file.extend(nonce);
+        let plaintext = serde_json::to_vec(credentials)?;
+        let ciphertext = self
+            .cipher(&salt)?
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: &plaintext,
+                    aad: &file,
+                },
+            )
+            .expect("Failed to encrypt credentials");
+        file.extend(ciphertext);
+        write_private_file(&self.path, &file)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), CredentialStoreError> {
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn load_or_create_key_file(path: &Path) -> io::Result<[u8; KEY_LEN]> {
+    match fs::read(path) {
+        Ok(key) => key.try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid key file {:?}", path),
+            )
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut key = [0_u8; KEY_LEN];
+            rand::thread_rng().fill_bytes(&mut key);
+            write_private_file(path, &key)?;
+            log::info!("generated credentials key: {:?}", path);
+            Ok(key)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Move the key file from `old_path` to `new_path`, unless there is a key at
+/// `new_path` already.
+pub fn migrate_key_file(old_path: &Path, new_path: &Path) {
+    if new_path.exists() || !old_path.exists() {
+        return;
+    }
+    let result = fs::read(old_path)
+        .and_then(|key| write_private_file(new_path, &key))
+        .and_then(|_| fs::remove_file(old_path));
+    match result {
+        Ok(()) => log::info!("moved credentials key: {:?}", new_path),
+        Err(err) => log::error!("failed to move credentials key: {}", err),
+    }
+}
+
+/// Write `contents` into a file only readable by the current user.
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        mkdir_if_not_exists(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
@@ -0,0 +1,391 @@
+pub mod credential_store;
+
+use std::{
+    env::{self, VarError},
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use directories::ProjectDirs;
+use druid::{Data, Lens, Size};
+use once_cell::sync::OnceCell;
+use psst_core::{
+    cache::mkdir_if_not_exists,
+    connection::Credentials,
+    oauth,
+    session::{SessionConfig, SessionConnection, DEFAULT_REQUEST_TIMEOUT},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::ui::theme;
+
+use super::{promise::Promise, Nav, SliderScrollScale};
+
+use credential_store::{
+    migrate_key_file, CredentialStore, EncryptedFileStore, KeySource, KeyringStore,
+};
+
+#[derive(Clone, Debug, Data, Lens)]
+pub struct Preferences {
+    pub active: PreferencesTab,
+    pub cache_size: Promise<u64, (), ()>,
+    pub auth: Authentication,
+}
+
+impl Preferences {
+    pub fn measure_cache_usage() -> Option<u64> {
+        Config::cache_dir().and_then(|path| get_dir_size(&path))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Data)]
+pub enum PreferencesTab {
+    General,
+    Account,
+    Cache,
+    About,
+}
+
+#[derive(Clone, Debug, Data, Lens)]
+pub struct Authentication {
+    pub username: String,
+    pub password: String,
+    pub access_token: String,
+    pub result: Promise<(), (), String>,
+}
+
+impl Authentication {
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            login_creds: if !self.access_token.is_empty() {
+                Credentials::from_access_token(self.access_token.clone())
+            } else {
+                Credentials::from_username_and_password(
+                    self.username.clone(),
+                    self.password.clone(),
+                )
+            },
+            proxy_url: Config::proxy(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            pinned_ap: Config::pinned_ap(),
+            last_ap_path: Config::last_ap_path(),
+            audio_key_cache_dir: Config::cache_dir(),
+        }
+    }
+
+    pub fn authenticate_and_get_credentials(config: SessionConfig) -> Result<Credentials, String> {
+        let connection = SessionConnection::open(config).map_err(|err| err.to_string())?;
+        Ok(connection.credentials)
+    }
+}
+
+const PROXY_ENV_VAR: &str = "SOCKS_PROXY";
+const AP_ENV_VAR: &str = "PSST_AP";
+const CREDENTIALS_PASSPHRASE_ENV_VAR: &str = "PSST_CREDENTIALS_PASSPHRASE";
+
+#[derive(Clone, Debug, Data, Lens, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Kept in the credential store, only read from the config file to migrate
+    // the credentials of older versions.
+    #[data(ignore)]
+    #[serde(skip_serializing)]
+    credentials: Option<Credentials>,
+    // Credentials changed since they were last loaded or saved.
+    #[data(ignore)]
+    #[serde(skip)]
+    credentials_changed: bool,
+    // Loading the stored credentials failed, e.g. because the keyring was
+    // locked.  They must not be cleared then, only replaced.
+    #[data(ignore)]
+    #[serde(skip)]
+    credentials_load_failed: bool,
+    pub audio_quality: AudioQuality,
+    pub theme: Theme,
+    pub volume: f64,
+    pub last_route: Option<Nav>,
+    pub show_track_cover: bool,
+    pub window_size: Size,
+    pub slider_scroll_scale: SliderScrollScale,
+    pub paginated_limit: usize,
+    /// Local port the browser is redirected to after logging in.  Its
+    /// redirect URI needs to be registered for the client ID.
+    pub oauth_redirect_port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            credentials: Default::default(),
+            credentials_changed: false,
+            credentials_load_failed: false,
+            audio_quality: Default::default(),
+            theme: Default::default(),
+            volume: 1.0,
+            last_route: Default::default(),
+            show_track_cover: Default::default(),
+            window_size: Size::new(theme::grid(80.0), theme::grid(100.0)),
+            slider_scroll_scale: Default::default(),
+            paginated_limit: 500,
+            oauth_redirect_port: oauth::DEFAULT_REDIRECT_PORT,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Data, Serialize, Deserialize)]
+pub enum AudioQuality {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for AudioQuality {
+    fn default() -> Self {
+        Self::High
+    }
+}
+
+const APP_NAME: &str = "Psst";
+const CONFIG_FILENAME: &str = "config.json";
+const LAST_AP_FILENAME: &str = "last-ap";
+const CREDENTIALS_FILENAME: &str = "credentials";
+const CREDENTIALS_KEY_FILENAME: &str = "credentials.key";
+const KEYRING_USER: &str = "credentials";
+
+static CREDENTIAL_STORE: OnceCell<Box<dyn CredentialStore>> = OnceCell::new();
+
+impl Config {
+    fn project_dirs() -> Option<ProjectDirs> {
+        ProjectDirs::from("", "", APP_NAME)
+    }
+
+    pub fn spotify_local_files_file(username: &str) -> Option<PathBuf> {
+        ProjectDirs::from("", "", "spotify").map(|dirs| {
+            let path = format!("Users/{}-user/local-files.bnk", username);
+            dirs.config_dir().join(path)
+        })
+    }
+
+    pub fn cache_dir() -> Option<PathBuf> {
+        Self::project_dirs().map(|dirs| dirs.cache_dir().to_path_buf())
+    }
+
+    pub fn config_dir() -> Option<PathBuf> {
+        Self::project_dirs().map(|dirs| dirs.cache_dir().to_path_buf())
+    }
+    fn config_path() -> Option<PathBuf> {
+        Self::config_dir().map(|dir| dir.join(CONFIG_FILENAME))
+    }
+    pub fn load() -> Option<Config> {
+        let path = Self::config_path().expect("Failed to get config path");
+        if let Ok(file) = File::open(&path) {
+            log::info!("loading config: {:?}", &path);
+            let reader = BufReader::new(file);
+            let mut config: Config =
+                serde_json::from_reader(reader).expect("Failed to read config");
+            config.load_credentials();
+            if config.oauth_redirect_port == 0 {
+                log::warn!(
+                    "OAuth redirect port 0 is not supported, using {}",
+                    oauth::DEFAULT_REDIRECT_PORT
+                );
+                config.oauth_redirect_port = oauth::DEFAULT_REDIRECT_PORT;
+            }
+            Some(config)
+        } else {
+            None
+        }
+    }
+
+    /// Directory of the credentials key file.  Kept apart from the config and
+    /// cache directory holding the encrypted credentials, so a copy of that
+    /// directory does not include the key.
+    fn credentials_key_dir() -> Option<PathBuf> {
+        Self::project_dirs().map(|dirs| dirs.data_local_dir().to_path_buf())
+    }
+
+    /// The system keyring, or the encrypted credentials file if the keyring is
+    /// not available.  The file key is derived from the passphrase in
+    /// `PSST_CREDENTIALS_PASSPHRASE` if it is set, otherwise it is kept in a
+    /// key file in the local data directory.  Opened only once.
+    pub fn credential_store() -> &'static dyn CredentialStore {
+        CREDENTIAL_STORE
+            .get_or_init(Self::open_credential_store)
+            .as_ref()
+    }
+
+    fn open_credential_store() -> Box<dyn CredentialStore> {
+        if let Some(store) = KeyringStore::open(APP_NAME, KEYRING_USER) {
+            return Box::new(store);
+        }
+        let dir = Self::config_dir().expect("Failed to get config dir");
+        let key = match env::var(CREDENTIALS_PASSPHRASE_ENV_VAR) {
+            Ok(passphrase) => KeySource::Passphrase(passphrase),
+            Err(_) => {
+                let key_dir = Self::credentials_key_dir().expect("Failed to get data dir");
+                let key_path = key_dir.join(CREDENTIALS_KEY_FILENAME);
+                // Older versions kept the key next to the credentials.
+                migrate_key_file(&dir.join(CREDENTIALS_KEY_FILENAME), &key_path);
+                KeySource::KeyFile(key_path)
+            }
+        };
+        Box::new(EncryptedFileStore::new(dir.join(CREDENTIALS_FILENAME), key))
+    }
+
+    fn load_credentials(&mut self) {
+        let store = Self::credential_store();
+        if self.credentials.is_some() {
+            // Older versions kept the credentials in plaintext in the config.
+            // Move them into the store, and rewrite the config without them.
+            log::info!("migrating credentials to {}", store.name());
+            self.credentials_changed = true;
+            if self.save_credentials() {
+                self.write_config();
+            }
+        } else {
+            match store.load() {
+                Ok(credentials) => self.credentials = credentials,
+                Err(err) => {
+                    log::error!("failed to load credentials: {}", err);
+                    self.credentials_load_failed = true;
+                }
+            }
+        }
+    }
+
+    pub fn save(&mut self) {
+        self.write_config();
+        if self.credentials_changed {
+            self.save_credentials();
+        }
+    }
+
+    fn write_config(&self) {
+        let dir = Self::config_dir().expect("Failed to get config dir");
+        let path = Self::config_path().expect("Failed to get config path");
+        mkdir_if_not_exists(&dir).expect("Failed to create config dir");
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(target_family = "unix")]
+        options.mode(0o600);
+
+        let file = options.open(&path).expect("Failed to create config");
+        let writer = BufWriter::new(file);
+
+        serde_json::to_writer_pretty(writer, self).expect("Failed to write config");
+        log::info!("saved config: {:?}", &path);
+    }
+
+    /// Write the credentials into the credential store.  Returns `false` if
+    /// they were not saved.
+    fn save_credentials(&mut self) -> bool {
+        let store = Self::credential_store();
+        let result = match &self.credentials {
+            Some(credentials) => store.store(credentials),
+            None if self.credentials_load_failed => {
+                log::warn!("not clearing credentials that failed to load");
+                return false;
+            }
+            None => store.clear(),
+        };
+        match result {
+            Ok(()) => {
+                log::info!("saved credentials: {}", store.name());
+                self.credentials_changed = false;
+                self.credentials_load_failed = false;
+                true
+            }
+            Err(err) => {
+                log::error!("failed to save credentials: {}", err);
+                false
+            }
+        }
+    }
+
+    pub fn has_credentials(&self) -> bool {
+        self.credentials.is_some()
+    }
+
+    pub fn store_credentials(&mut self, credential: Credentials) {
+        self.credentials.replace(credential);
+        self.credentials_changed = true;
+    }
+
+    pub fn clear_credentials(&mut self) {
+        self.credentials = Default::default();
+        self.credentials_changed = true;
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.credentials
+            .as_ref()
+            .and_then(|c| c.username.as_deref())
+    }
+
+    pub fn session(&self) -> SessionConfig {
+        SessionConfig {
+            login_creds: self.credentials.clone().expect("Missing credentials"),
+            proxy_url: Config::proxy(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            pinned_ap: Config::pinned_ap(),
+            last_ap_path: Config::last_ap_path(),
+            audio_key_cache_dir: Config::cache_dir(),
+        }
+    }
+
+    pub fn proxy() -> Option<String> {
+        env::var(PROXY_ENV_VAR).map_or_else(
+            |err| match err {
+                VarError::NotPresent => None,
+                VarError::NotUnicode(_) => {
+                    log::error!("proxy URL is not a valid unicode");
+                    None
+                }
+            },
+            Some,
+        )
+    }
+
+    pub fn pinned_ap() -> Option<String> {
+        env::var(AP_ENV_VAR).map_or_else(
+            |err| match err {
+                VarError::NotPresent => None,
+                VarError::NotUnicode(_) => {
+                    log::error!("pinned AP is not a valid unicode");
+                    None
+                }
+            },
+            Some,
+        )
+    }
+
+    fn last_ap_path() -> Option<PathBuf> {
+        Self::cache_dir().map(|dir| dir.join(LAST_AP_FILENAME))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Data, Serialize, Deserialize)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::Light
+    }
+}
+
+fn get_dir_size(path: &Path) -> Option<u64> {
+    fs::read_dir(path).ok()?.try_fold(0, |acc, entry| {
+        let entry = entry.ok()?;
+        let size = if entry.file_type().ok()?.is_dir() {
+            get_dir_size(&entry.path())?
+        } else {
+            entry.metadata().ok()?.len()
+        };
+        Some(acc + size)
+    })
+}
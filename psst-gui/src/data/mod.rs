@@ -25,7 +25,7 @@ pub use crate::data::{
 use config::{Authentication, Preferences, PreferencesTab};
 use druid::{im::Vector, Data, Lens};
 use playback::Playback;
-use psst_core::session::SessionService;
+use psst_core::session::{state::ConnectionState, SessionService};
 use user::UserProfile;
 
 pub use crate::data::config::Config;
@@ -34,6 +34,8 @@ pub use crate::data::config::Config;
 pub struct AppState {
     #[data(ignore)]
     pub session: SessionService,
+    #[data(eq)]
+    pub connection_state: ConnectionState,
     pub config: Config,
     pub preferences: Preferences,
     pub playback: Playback,
@@ -55,6 +57,7 @@ impl AppState {
         let playback = Playback { now_playing: None };
         Self {
             session: SessionService::empty(),
+            connection_state: ConnectionState::Disconnected,
             config,
             preferences: Preferences {
                 active: PreferencesTab::General,
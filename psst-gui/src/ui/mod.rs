@@ -110,7 +110,7 @@ fn root_widget() -> impl Widget<AppState> {
         .with_child(controls)
         .background(theme::BACKGROUND_DARK);
 
-    ThemeScope::new(sidebar).controller(SessionController)
+    ThemeScope::new(sidebar).controller(SessionController::new())
 }
 
 fn sidebar_menu_widget() -> impl Widget<AppState> {
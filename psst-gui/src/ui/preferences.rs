@@ -1,6 +1,6 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
 use druid::{
@@ -165,7 +165,16 @@ fn account_tab_widget(tab: AccountTab) -> impl Widget<AppState> {
         .with_spacer(theme::grid(1.0))
         .with_child(
             Async::new(
-                || Label::new("Logging in...").with_text_size(theme::TEXT_SIZE_SMALL),
+                || {
+                    Flex::row()
+                        .with_child(
+                            Label::new("Logging in...").with_text_size(theme::TEXT_SIZE_SMALL),
+                        )
+                        .with_default_spacer()
+                        .with_child(Button::new("Cancel").on_click(|ctx, _, _| {
+                            ctx.submit_command(Authenticate::CANCEL);
+                        }))
+                },
                 || Label::new("").with_text_size(theme::TEXT_SIZE_SMALL),
                 || {
                     Label::dynamic(|err: &String, _| err.to_owned())
@@ -189,19 +198,28 @@ fn account_tab_widget(tab: AccountTab) -> impl Widget<AppState> {
     col.controller(Authenticate::new(tab))
 }
 
+// How long to wait for the user to log in with the browser.
+const OAUTH_LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
 struct Authenticate {
     tab: AccountTab,
     thread: Option<JoinHandle<()>>,
+    cancel: Option<oauth::AuthCodeListenerCancel>,
 }
 
 impl Authenticate {
     fn new(tab: AccountTab) -> Self {
-        Self { tab, thread: None }
+        Self {
+            tab,
+            thread: None,
+            cancel: None,
+        }
     }
 }
 
 impl Authenticate {
     const REQUEST: Selector = Selector::new("app.preferences.authenticate-request");
+    const CANCEL: Selector = Selector::new("app.preferences.authenticate-cancel");
     const RESPONSE: Selector<Result<Credentials, String>> =
         Selector::new("app.preferences.authenticate-response");
 }
@@ -217,43 +235,74 @@ impl<W: Widget<AppState>> Controller<AppState, W> for Authenticate {
     ) {
         match event {
             Event::Command(cmd) if cmd.is(Self::REQUEST) => {
+                if self.thread.is_some() {
+                    // A login is in progress already.
+                    ctx.set_handled();
+                    return;
+                }
                 data.preferences.auth.result.defer_default();
 
-                let (auth_url, pkce_verifier) = oauth::generate_auth_url(8888);
+                let listener = match oauth::AuthCodeListener::bind(data.config.oauth_redirect_port)
+                {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        data.preferences
+                            .auth
+                            .result
+                            .reject((), format!("Failed to log in: {}", err));
+                        return;
+                    }
+                };
+                let port = listener.port();
+                let (auth_url, pkce_verifier, csrf_state) = match oauth::generate_auth_url(port) {
+                    Ok(auth) => auth,
+                    Err(err) => {
+                        data.preferences
+                            .auth
+                            .result
+                            .reject((), format!("Failed to log in: {}", err));
+                        return;
+                    }
+                };
                 if open::that(&auth_url).is_err() {
-                    data.error_alert("Failed to open browser");
+                    data.preferences
+                        .auth
+                        .result
+                        .reject((), "Failed to open browser".to_string());
                     return;
                 }
 
                 let config = data.preferences.auth.session_config();
                 let widget_id = ctx.widget_id();
                 let event_sink = ctx.get_external_handle();
+                self.cancel.replace(listener.cancel_handle());
                 let thread = thread::spawn(move || {
-                    match oauth::get_authcode_listener(
-                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8888),
-                        std::time::Duration::from_secs(300),
-                    ) {
-                        Ok(code) => {
-                            let token = oauth::exchange_code_for_token(8888, code, pkce_verifier);
-                            let response =
-                                Authentication::authenticate_and_get_credentials(SessionConfig {
-                                    login_creds: Credentials::from_access_token(token),
-                                    ..config
-                                });
-                            event_sink
-                                .submit_command(Self::RESPONSE, response, widget_id)
-                                .unwrap();
-                        }
-                        Err(e) => {
-                            event_sink
-                                .submit_command(Self::RESPONSE, Err(e), widget_id)
-                                .unwrap();
-                        }
-                    }
+                    let response = listener
+                        .wait_for_code(&csrf_state, OAUTH_LOGIN_TIMEOUT)
+                        .and_then(|code| oauth::exchange_code_for_token(port, code, pkce_verifier))
+                        .map_err(|err| err.to_string())
+                        .and_then(|token| {
+                            Authentication::authenticate_and_get_credentials(SessionConfig {
+                                login_creds: Credentials::from_oauth_token(token),
+                                ..config
+                            })
+                        });
+                    event_sink
+                        .submit_command(Self::RESPONSE, response, widget_id)
+                        .unwrap();
                 });
+                self.thread.replace(thread);
+                ctx.set_handled();
+            }
+            Event::Command(cmd) if cmd.is(Self::CANCEL) => {
+                if let Some(cancel) = self.cancel.take() {
+                    cancel.cancel();
+                }
+                ctx.set_handled();
             }
             Event::Command(cmd) if cmd.is(Self::RESPONSE) => {
                 self.thread.take();
+                self.cancel.take();
 
                 let result = cmd
                     .get_unchecked(Self::RESPONSE)
@@ -1,9 +1,11 @@
 use druid::{
     commands,
-    widget::{Either, Flex, Label},
+    widget::{Flex, Label, LineBreaking},
     Data, LensExt, Selector, Widget, WidgetExt,
 };
 
+use psst_core::session::state::ConnectionState;
+
 use crate::{
     data::{AppState, Library, UserProfile},
     webapi::WebApi,
@@ -18,16 +20,11 @@ use super::theme;
 pub const LOAD_PROFILE: Selector = Selector::new("app.user.load-profile");
 
 pub fn user_widget() -> impl Widget<AppState> {
-    let is_connected = Either::new(
-        // TODO: Avoid the locking here.
-        |state: &AppState, _| state.session.is_connected(),
-        Label::new("Connected")
-            .with_text_color(theme::PLACEHOLDER_COLOR)
-            .with_text_size(theme::TEXT_SIZE_SMALL),
-        Label::new("Disconnected")
+    let connection_state =
+        Label::dynamic(|state: &AppState, _| connection_state_text(&state.connection_state))
+            .with_line_break_mode(LineBreaking::WordWrap)
             .with_text_color(theme::PLACEHOLDER_COLOR)
-            .with_text_size(theme::TEXT_SIZE_SMALL),
-    );
+            .with_text_size(theme::TEXT_SIZE_SMALL);
 
     let user_profile = Async::new(
         || Empty,
@@ -49,7 +46,7 @@ pub fn user_widget() -> impl Widget<AppState> {
     Flex::row()
         .with_child(
             Flex::column()
-                .with_child(is_connected)
+                .with_child(connection_state)
                 .with_default_spacer()
                 .with_child(user_profile)
                 .padding(theme::grid(1.0)),
@@ -57,6 +54,16 @@ pub fn user_widget() -> impl Widget<AppState> {
         .with_child(preferences_widget(&icons::PREFERENCES))
 }
 
+fn connection_state_text(state: &ConnectionState) -> String {
+    match state {
+        ConnectionState::Disconnected => "Disconnected".to_string(),
+        ConnectionState::Connecting => "Connecting…".to_string(),
+        ConnectionState::Authenticating => "Logging in…".to_string(),
+        ConnectionState::Connected { .. } => "Connected".to_string(),
+        ConnectionState::Failed { error } => format!("Connection failed: {}", error),
+    }
+}
+
 fn preferences_widget<T: Data>(svg: &SvgIcon) -> impl Widget<T> {
     svg.scale((theme::grid(3.0), theme::grid(3.0)))
         .padding(theme::grid(1.0))